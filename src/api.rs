@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use crate::docker::Annotations;
+
 #[derive(serde::Serialize)]
 #[allow(non_camel_case_types)]
 pub enum DockerErrorMessageType {
@@ -18,6 +20,7 @@ pub enum DockerErrorMessageType {
     UNAUTHORIZED,
     DENIED,
     UNSUPPORTED,
+    UNKNOWN,
 }
 
 #[derive(serde::Serialize)]
@@ -50,7 +53,91 @@ pub struct DockerTagsList {
     pub tags: Vec<String>,
 }
 
-#[derive(serde::Serialize)]
+/// Digest of the manifest a tag points to
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerTagDigest {
+    pub digest: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
 pub struct DockerCatalog {
     pub repositories: Vec<String>,
 }
+
+/// Repository listed by the extended catalog
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerCatalogEntry {
+    pub name: String,
+    /// Time of the last push to the repository, as a UNIX timestamp
+    pub last_updated: Option<u64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerExtendedCatalog {
+    pub repositories: Vec<DockerCatalogEntry>,
+}
+
+/// Manifests and tags of a repository referencing a blob
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerBlobReferrers {
+    pub name: String,
+    pub digest: String,
+    pub manifests: Vec<String>,
+    pub tags: Vec<String>,
+}
+
+/// Manifest revision stored in a repository
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerManifestRevision {
+    pub digest: String,
+    pub tags: Vec<String>,
+}
+
+/// Every manifest revision stored in a repository, tagged or not
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerManifestRevisions {
+    pub name: String,
+    pub revisions: Vec<DockerManifestRevision>,
+}
+
+/// Descriptor of a manifest referring to another one
+#[allow(non_snake_case)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerReferrer {
+    pub mediaType: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artifactType: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub annotations: Option<Annotations>,
+}
+
+/// Image index listing the manifests referring to a manifest through their `subject`
+#[allow(non_snake_case)]
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct DockerReferrersIndex {
+    pub schemaVersion: usize,
+    pub mediaType: String,
+    pub manifests: Vec<DockerReferrer>,
+}
+
+/// Summary of the content of the registry
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct RegistryStatus {
+    pub repositories: usize,
+    pub blobs: usize,
+    /// Total size of the stored blobs
+    pub total_bytes: u64,
+    /// Completion time of the last garbage collection, as a UNIX timestamp
+    pub last_gc_at: Option<u64>,
+}
+
+/// Version of the running registry
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct VersionInfo {
+    pub name: String,
+    pub version: String,
+    /// Commit the registry was built from, when provided at build time
+    pub git: Option<String>,
+}
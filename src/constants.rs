@@ -1,2 +1,59 @@
-/// JWT auth token lifetime
+/// Default JWT auth token lifetime
 pub const AUTH_TOKENS_DURATION: u64 = 300;
+
+/// JWT refresh token lifetime
+pub const REFRESH_TOKENS_DURATION: u64 = 60 * 60 * 24 * 30;
+
+/// Default maximum size of buffered request bodies
+pub const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Default path of the registry inside the storage directory
+pub const DEFAULT_STORAGE_BASE_PATH: &str = "docker/registry/v2/";
+
+/// Maximum length of image names
+pub const MAX_IMAGE_NAME_LENGTH: usize = 255;
+
+/// Maximum number of path components of image names
+pub const MAX_IMAGE_NAME_DEPTH: usize = 16;
+
+/// Delay, in seconds, after which clients should retry uploads refused because of concurrency limits
+pub const UPLOADS_RETRY_AFTER: u64 = 10;
+
+/// Minimum length of the passwords of new users
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Environment variable holding the name of the user to add
+pub const NEW_USER_ENV: &str = "DOCKERUST_NEW_USER";
+
+/// Environment variable holding the password of the user to add
+pub const NEW_PASSWORD_ENV: &str = "DOCKERUST_NEW_PASSWORD";
+
+/// Blobs smaller than this size, in bytes, are served from memory instead of being streamed
+pub const SMALL_BLOB_SIZE: u64 = 1024 * 1024;
+
+/// Default maximum number of layers of a pushed image manifest. Images rarely have more than
+/// a hundred layers, overlay filesystems being limited to 128
+pub const MAX_MANIFEST_LAYERS: usize = 1000;
+
+/// Default maximum number of manifests referenced by a pushed manifest list or image index
+pub const MAX_INDEX_MANIFESTS: usize = 1000;
+
+/// Default age under which the garbage collector keeps unreferenced blobs, which may belong to a
+/// push whose manifest has not been sent yet, in seconds
+pub const GC_GRACE_PERIOD: u64 = 60 * 60;
+
+/// Default time after which the uploads left without activity are cancelled, in seconds
+pub const UPLOADS_IDLE_TIMEOUT: u64 = 60 * 60;
+
+/// Age after which the uploads left behind by a previous run are removed at startup, in seconds
+pub const STALE_UPLOADS_AGE: u64 = 60 * 60 * 24;
+
+/// Default size of the buffer blob uploads are written to the disk through, in bytes
+pub const UPLOAD_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Bounds of the configurable upload buffer size, in bytes
+pub const MIN_UPLOAD_BUFFER_SIZE: usize = 4 * 1024;
+pub const MAX_UPLOAD_BUFFER_SIZE: usize = 64 * 1024 * 1024;
+
+/// Name of the lock file held by the garbage collector in the registry directory
+pub const GC_LOCK_FILE: &str = ".gc.lock";
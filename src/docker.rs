@@ -1,9 +1,62 @@
+pub const DOCKER_MANIFEST_V1: &str = "application/vnd.docker.distribution.manifest.v1+json";
+pub const DOCKER_MANIFEST_V1_SIGNED: &str = "application/vnd.docker.distribution.manifest.v1+prettyjws";
+pub const DOCKER_MANIFEST_V2: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub const DOCKER_MANIFEST_LIST_V2: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const OCI_IMAGE_MANIFEST_V1: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_IMAGE_INDEX_V1: &str = "application/vnd.oci.image.index.v1+json";
+
+pub type Annotations = std::collections::HashMap<String, String>;
+
+/// Media types of the manifests that can be pushed to the registry
+pub const MANIFEST_MEDIA_TYPES: [&str; 4] = [
+    DOCKER_MANIFEST_V2,
+    DOCKER_MANIFEST_LIST_V2,
+    OCI_IMAGE_MANIFEST_V1,
+    OCI_IMAGE_INDEX_V1,
+];
+
 #[derive(serde::Deserialize, Clone)]
 #[allow(non_snake_case)]
 pub struct DockerBlobRef {
     pub mediaType: String,
     pub digest: String,
     pub size: Option<usize>,
+    pub artifactType: Option<String>,
+    pub annotations: Option<Annotations>,
+    /// Locations of a foreign layer, which is not stored by the registry
+    pub urls: Option<Vec<String>>,
+    /// Platform of a manifest referenced by a manifest list
+    pub platform: Option<Platform>,
+}
+
+impl DockerBlobRef {
+    /// Check if this is a foreign (nondistributable) layer, pulled from its `urls`
+    pub fn is_foreign(&self) -> bool {
+        self.urls.as_ref().is_some_and(|urls| !urls.is_empty())
+    }
+}
+
+/// Platform an image manifest is built for
+#[derive(serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    pub variant: Option<String>,
+    /// Version of the operating system, mostly set for Windows images
+    #[serde(rename = "os.version")]
+    pub os_version: Option<String>,
+}
+
+impl Platform {
+    /// Check if the platform matches a `os/architecture[/variant]` specification. The variant
+    /// is only compared when the specification includes it
+    pub fn matches(&self, spec: &str) -> bool {
+        let mut parts = spec.split('/');
+        parts.next() == Some(self.os.as_str())
+            && parts.next() == Some(self.architecture.as_str())
+            && parts.next().is_none_or(|v| self.variant.as_deref() == Some(v))
+            && parts.next().is_none()
+    }
 }
 
 #[allow(non_snake_case)]
@@ -13,6 +66,9 @@ pub struct DockerManifest {
     pub mediaType: String,
     pub config: DockerBlobRef,
     pub layers: Vec<DockerBlobRef>,
+    pub artifactType: Option<String>,
+    pub subject: Option<DockerBlobRef>,
+    pub annotations: Option<Annotations>,
 }
 
 #[allow(non_snake_case)]
@@ -21,31 +77,50 @@ pub struct DockerManifestList {
     pub schemaVersion: usize,
     pub mediaType: String,
     pub manifests: Vec<DockerBlobRef>,
+    pub artifactType: Option<String>,
+    pub subject: Option<DockerBlobRef>,
+    pub annotations: Option<Annotations>,
 }
 
 #[allow(non_snake_case)]
 #[derive(serde::Deserialize)]
 pub struct DockerManifestOrManifestList {
     pub schemaVersion: usize,
+    /// Optional for OCI manifests, see [`Self::media_type`]
+    #[serde(default)]
     pub mediaType: String,
     pub config: Option<DockerBlobRef>,
     pub layers: Option<Vec<DockerBlobRef>>,
     pub manifests: Option<Vec<DockerBlobRef>>,
+    pub artifactType: Option<String>,
+    pub subject: Option<DockerBlobRef>,
+    pub annotations: Option<Annotations>,
 }
 
 impl DockerManifestOrManifestList {
+    /// Get the media type of the manifest, which is inferred for OCI manifests
+    /// that do not specify it
+    pub fn media_type(&self) -> &str {
+        match (self.mediaType.is_empty(), self.manifests.is_some()) {
+            (false, _) => &self.mediaType,
+            (true, true) => OCI_IMAGE_INDEX_V1,
+            (true, false) => OCI_IMAGE_MANIFEST_V1,
+        }
+    }
+
     pub fn get_manifest(&self) -> Option<DockerManifest> {
-        if self
-            .mediaType
-            .eq("application/vnd.docker.distribution.manifest.v2+json")
+        if [DOCKER_MANIFEST_V2, OCI_IMAGE_MANIFEST_V1].contains(&self.media_type())
             && self.config.is_some()
             && self.layers.is_some()
         {
             return Some(DockerManifest {
                 schemaVersion: self.schemaVersion,
-                mediaType: self.mediaType.to_string(),
+                mediaType: self.media_type().to_string(),
                 config: self.config.clone().unwrap(),
                 layers: self.layers.clone().unwrap(),
+                artifactType: self.artifactType.clone(),
+                subject: self.subject.clone(),
+                annotations: self.annotations.clone(),
             });
         }
 
@@ -53,18 +128,121 @@ impl DockerManifestOrManifestList {
     }
 
     pub fn get_manifests_list(&self) -> Option<DockerManifestList> {
-        if self
-            .mediaType
-            .eq("application/vnd.docker.distribution.manifest.list.v2+json")
-            && self.manifests.is_some()
-        {
+        if [DOCKER_MANIFEST_LIST_V2, OCI_IMAGE_INDEX_V1].contains(&self.media_type()) && self.manifests.is_some() {
             return Some(DockerManifestList {
                 schemaVersion: self.schemaVersion,
-                mediaType: self.mediaType.to_string(),
+                mediaType: self.media_type().to_string(),
                 manifests: self.manifests.clone().unwrap(),
+                artifactType: self.artifactType.clone(),
+                subject: self.subject.clone(),
+                annotations: self.annotations.clone(),
             });
         }
 
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_oci_artifact_manifest() {
+        let manifest: DockerManifestOrManifestList = serde_json::from_str(
+            r#"{
+              "schemaVersion": 2,
+              "mediaType": "application/vnd.oci.image.manifest.v1+json",
+              "artifactType": "application/vnd.example.sbom.v1",
+              "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2
+              },
+              "layers": [
+                {
+                  "mediaType": "application/spdx+json",
+                  "digest": "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                  "size": 5,
+                  "annotations": { "org.opencontainers.image.title": "sbom.json" }
+                }
+              ],
+              "subject": {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "digest": "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                "size": 1234
+              },
+              "annotations": { "org.opencontainers.image.created": "2024-01-01T00:00:00Z" }
+            }"#,
+        )
+        .unwrap();
+
+        let manifest = manifest.get_manifest().unwrap();
+        assert_eq!(
+            manifest.artifactType.as_deref(),
+            Some("application/vnd.example.sbom.v1")
+        );
+        assert_eq!(
+            manifest.subject.unwrap().digest,
+            "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            manifest.annotations.unwrap()["org.opencontainers.image.created"],
+            "2024-01-01T00:00:00Z"
+        );
+        assert_eq!(
+            manifest.layers[0].annotations.as_ref().unwrap()["org.opencontainers.image.title"],
+            "sbom.json"
+        );
+        assert!(manifest.config.annotations.is_none());
+    }
+
+    #[test]
+    fn parse_manifest_list_platforms() {
+        let manifest: DockerManifestOrManifestList = serde_json::from_str(
+            r#"{
+              "schemaVersion": 2,
+              "mediaType": "application/vnd.docker.distribution.manifest.list.v2+json",
+              "manifests": [
+                {
+                  "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                  "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                  "size": 2,
+                  "platform": { "architecture": "arm64", "os": "linux", "variant": "v8" }
+                },
+                {
+                  "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+                  "digest": "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+                  "size": 5,
+                  "platform": { "architecture": "amd64", "os": "windows", "os.version": "10.0.17763.1817" }
+                },
+                {
+                  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                  "digest": "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                  "size": 0
+                }
+              ]
+            }"#,
+        )
+        .unwrap();
+
+        let list = manifest.get_manifests_list().unwrap();
+        assert_eq!(
+            list.manifests[0].platform,
+            Some(Platform {
+                architecture: "arm64".to_string(),
+                os: "linux".to_string(),
+                variant: Some("v8".to_string()),
+                os_version: None,
+            })
+        );
+        let windows = list.manifests[1].platform.as_ref().unwrap();
+        assert_eq!(windows.os_version.as_deref(), Some("10.0.17763.1817"));
+        assert!(windows.variant.is_none());
+        assert!(list.manifests[2].platform.is_none());
+
+        assert!(windows.matches("windows/amd64"));
+        assert!(!windows.matches("windows/amd64/v2"));
+        assert!(list.manifests[0].platform.as_ref().unwrap().matches("linux/arm64"));
+    }
+}
@@ -0,0 +1,296 @@
+//! Diagnosis of the problems of a storage, for the `doctor` command
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::Path;
+
+use crate::error::RegistryResult;
+use crate::storage::{
+    get_blob_list, get_docker_images_list, is_blob_intact, referenced_blobs, BlobReference, DockerImage,
+};
+
+/// Category of a storage problem
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProblemKind {
+    /// The storage directory is missing or can not be written
+    Storage,
+    /// The directories of the registry are missing or misplaced
+    Layout,
+    /// A tag points to a manifest that is not a revision of its image
+    DanglingTag,
+    /// A manifest can not be read or parsed
+    InvalidManifest,
+    /// A manifest references a blob that is not stored
+    MissingBlob,
+    /// The content of a blob does not match its digest
+    CorruptedBlob,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Storage => "Storage",
+            Self::Layout => "Layout",
+            Self::DanglingTag => "Dangling tags",
+            Self::InvalidManifest => "Invalid manifests",
+            Self::MissingBlob => "Missing blobs",
+            Self::CorruptedBlob => "Corrupted blobs",
+        })
+    }
+}
+
+/// Problem found in a storage
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub detail: String,
+}
+
+impl Problem {
+    fn new(kind: ProblemKind, detail: impl Into<String>) -> Self {
+        Self {
+            kind,
+            detail: detail.into(),
+        }
+    }
+}
+
+fn check_storage(storage: &Path, problems: &mut Vec<Problem>) {
+    if !storage.is_dir() {
+        problems.push(Problem::new(
+            ProblemKind::Storage,
+            format!("{} is not a directory", storage.display()),
+        ));
+        return;
+    }
+
+    if let Err(e) = mktemp::Temp::new_file_in(storage) {
+        problems.push(Problem::new(
+            ProblemKind::Storage,
+            format!("{} is not writable: {}", storage.display(), e),
+        ));
+    }
+}
+
+/// Check the layout of the registry, returning whether the other checks can proceed
+fn check_layout(registry: &Path, problems: &mut Vec<Problem>) -> bool {
+    if !registry.is_dir() {
+        problems.push(Problem::new(
+            ProblemKind::Layout,
+            format!("registry directory {} is missing", registry.display()),
+        ));
+        return false;
+    }
+
+    let mut valid = true;
+    for dir in ["repositories", "blobs"] {
+        let path = registry.join(dir);
+        if path.exists() && !path.is_dir() {
+            problems.push(Problem::new(
+                ProblemKind::Layout,
+                format!("{} is not a directory", path.display()),
+            ));
+            valid = false;
+        }
+    }
+
+    valid
+}
+
+fn check_image(image: &DockerImage, problems: &mut Vec<Problem>) -> RegistryResult<()> {
+    for tag in image.tags_list()? {
+        match BlobReference::from_file(&image.manifest_tag_link_path(&tag)) {
+            Ok(blob) if image.manifest_revision_path(&blob).exists() => {}
+            Ok(blob) => problems.push(Problem::new(
+                ProblemKind::DanglingTag,
+                format!(
+                    "{}:{} points to unknown revision {}",
+                    image.image,
+                    tag,
+                    blob.to_digest()
+                ),
+            )),
+            Err(e) => problems.push(Problem::new(
+                ProblemKind::DanglingTag,
+                format!("{}:{} has an invalid link: {}", image.image, tag, e),
+            )),
+        }
+    }
+
+    for manifest in image.manifests_revision_list()? {
+        let mut blobs = BTreeSet::new();
+        if let Err(e) = referenced_blobs(&manifest, &image.storage_path, &mut blobs) {
+            problems.push(Problem::new(
+                ProblemKind::InvalidManifest,
+                format!("{}@{} can not be parsed: {}", image.image, manifest.to_digest(), e),
+            ));
+            continue;
+        }
+
+        for digest in blobs {
+            let blob: BlobReference = digest.parse()?;
+            if !blob.data_path(&image.storage_path).exists() {
+                problems.push(Problem::new(
+                    ProblemKind::MissingBlob,
+                    format!(
+                        "{}@{} references missing blob {}",
+                        image.image,
+                        manifest.to_digest(),
+                        digest
+                    ),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_blob(blob: &BlobReference, registry: &Path, problems: &mut Vec<Problem>) -> RegistryResult<()> {
+    if !is_blob_intact(registry, blob)? {
+        problems.push(Problem::new(
+            ProblemKind::CorruptedBlob,
+            format!("{} has content that does not match its digest", blob.to_digest()),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Run every check on a storage and its registry, and get the problems found, sorted by kind
+pub fn diagnose(storage: &Path, registry: &Path) -> RegistryResult<Vec<Problem>> {
+    let mut problems = vec![];
+
+    check_storage(storage, &mut problems);
+    if check_layout(registry, &mut problems) {
+        for image in get_docker_images_list(registry)? {
+            check_image(&DockerImage::new(registry, &image), &mut problems)?;
+        }
+
+        for blob in get_blob_list(registry)? {
+            check_blob(&blob, registry, &mut problems)?;
+        }
+    }
+
+    problems.sort_by_key(|p| p.kind);
+    Ok(problems)
+}
+
+/// Format the problems found in a storage by category
+pub fn report(problems: &[Problem]) -> String {
+    if problems.is_empty() {
+        return "No problem found.\n".to_string();
+    }
+
+    let mut report = String::new();
+    for (i, problem) in problems.iter().enumerate() {
+        if i == 0 || problems[i - 1].kind != problem.kind {
+            report.push_str(&format!("{}:\n", problem.kind));
+        }
+        report.push_str(&format!("  - {}\n", problem.detail));
+    }
+    report.push_str(&format!("{} problem(s) found.\n", problems.len()));
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{store_blob, store_manifest};
+    use crate::utils::write_file_atomic;
+
+    /// Seed a registry holding a valid image, in a storage directory
+    fn seeded_storage() -> (mktemp::Temp, std::path::PathBuf, BlobReference, BlobReference) {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let registry = storage.join("registry");
+        let layer = store_blob(&registry, "layer".as_bytes()).unwrap();
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json",
+            "config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":5}},
+            "layers":[]}}"#,
+            layer.to_digest()
+        );
+        let manifest = store_manifest(&registry, "foo", "latest", manifest.as_bytes()).unwrap();
+
+        (storage, registry, layer, manifest)
+    }
+
+    fn kinds(storage: &Path, registry: &Path) -> Vec<ProblemKind> {
+        diagnose(storage, registry)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.kind)
+            .collect()
+    }
+
+    #[test]
+    fn healthy_storage_has_no_problem() {
+        let (storage, registry, _, _) = seeded_storage();
+        assert!(kinds(&storage, &registry).is_empty());
+        assert_eq!(report(&[]), "No problem found.\n");
+    }
+
+    #[test]
+    fn missing_directories_are_detected() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        assert_eq!(kinds(&storage, &storage.join("registry")), vec![ProblemKind::Layout]);
+        assert_eq!(
+            kinds(&storage.join("missing"), &storage.join("registry")),
+            vec![ProblemKind::Storage, ProblemKind::Layout]
+        );
+
+        std::fs::create_dir(storage.join("registry")).unwrap();
+        std::fs::write(storage.join("registry/blobs"), "").unwrap();
+        assert_eq!(kinds(&storage, &storage.join("registry")), vec![ProblemKind::Layout]);
+    }
+
+    #[test]
+    fn dangling_tags_are_detected() {
+        let (storage, registry, _, manifest) = seeded_storage();
+        let image = DockerImage::new(&registry, "foo");
+        std::fs::remove_file(image.manifest_revision_path(&manifest)).unwrap();
+        write_file_atomic(&image.manifest_tag_link_path("broken"), "corrupted", false).unwrap();
+
+        assert_eq!(
+            kinds(&storage, &registry),
+            vec![ProblemKind::DanglingTag, ProblemKind::DanglingTag]
+        );
+    }
+
+    #[test]
+    fn missing_blobs_are_detected() {
+        let (storage, registry, layer, _) = seeded_storage();
+        std::fs::remove_dir_all(layer.data_path(&registry).parent().unwrap()).unwrap();
+
+        let problems = diagnose(&storage, &registry).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].kind, ProblemKind::MissingBlob);
+        assert!(problems[0].detail.contains(&layer.to_digest()));
+    }
+
+    #[test]
+    fn invalid_manifests_are_detected() {
+        let (storage, registry, _, manifest) = seeded_storage();
+        std::fs::write(manifest.data_path(&registry), "not a manifest").unwrap();
+
+        let problems = diagnose(&storage, &registry).unwrap();
+        let kinds = problems.iter().map(|p| p.kind).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![ProblemKind::InvalidManifest, ProblemKind::CorruptedBlob]);
+        assert!(problems[0].detail.contains(&manifest.to_digest()));
+    }
+
+    #[test]
+    fn corrupted_blobs_are_detected() {
+        let (storage, registry, layer, _) = seeded_storage();
+        std::fs::write(layer.data_path(&registry), "tampered").unwrap();
+
+        let problems = diagnose(&storage, &registry).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].kind, ProblemKind::CorruptedBlob);
+
+        let report = report(&problems);
+        assert!(report.starts_with("Corrupted blobs:\n  - "));
+        assert!(report.ends_with("1 problem(s) found.\n"));
+    }
+}
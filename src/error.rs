@@ -0,0 +1,59 @@
+//! Registry errors
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+
+use crate::api::{DockerErrorMessageType, DockerErrorResponse};
+
+/// Errors that may happen while manipulating the registry
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("invalid digest: {0}")]
+    InvalidDigest(String),
+    #[error("blob unknown: {0}")]
+    BlobMissing(String),
+    #[error("manifest unknown: {0}")]
+    ManifestUnknown(String),
+    #[error("manifest invalid: {0}")]
+    ManifestInvalid(String),
+    #[error("invalid repository name: {0}")]
+    NameInvalid(String),
+    #[error("invalid tag: {0}")]
+    TagInvalid(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<serde_json::Error> for RegistryError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Io(e.into())
+    }
+}
+
+impl ResponseError for RegistryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::InvalidDigest(_) | Self::ManifestInvalid(_) | Self::NameInvalid(_) | Self::TagInvalid(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::BlobMissing(_) | Self::ManifestUnknown(_) => StatusCode::NOT_FOUND,
+            Self::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let (code, message) = match self {
+            Self::InvalidDigest(_) => (DockerErrorMessageType::DIGEST_INVALID, "invalid digest"),
+            Self::BlobMissing(_) => (DockerErrorMessageType::BLOB_UNKNOWN, "blob unknown"),
+            Self::ManifestUnknown(_) => (DockerErrorMessageType::MANIFEST_UNKNOWN, "manifest unknown"),
+            Self::ManifestInvalid(_) => (DockerErrorMessageType::MANIFEST_INVALID, "manifest invalid"),
+            Self::NameInvalid(_) => (DockerErrorMessageType::NAME_INVALID, "invalid repository name"),
+            Self::TagInvalid(_) => (DockerErrorMessageType::TAG_INVALID, "invalid tag"),
+            Self::Io(_) => (DockerErrorMessageType::UNKNOWN, "internal server error"),
+        };
+
+        HttpResponse::build(self.status_code()).json(DockerErrorResponse::new_simple(code, message))
+    }
+}
+
+pub type RegistryResult<T> = Result<T, RegistryError>;
@@ -0,0 +1,154 @@
+//! Detection of the clients that stopped reading the responses they requested
+
+use std::net::Shutdown;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::net::TcpStream;
+use actix_web::web::Bytes;
+use futures::Stream;
+
+use crate::log_error;
+use crate::request_id::in_current_request;
+
+/// Size of the pieces bodies are sent in, so that each poll of a watched body reflects the
+/// progress of the client
+const WATCHED_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Handle on the socket of a connection, recorded by [`actix_web::HttpServer::on_connect`],
+/// to drop the connection once a response stops being read
+#[derive(Clone)]
+pub struct IdleWatchdog {
+    socket: Arc<socket2::Socket>,
+    timeout: Duration,
+}
+
+impl IdleWatchdog {
+    pub fn new(stream: &TcpStream, timeout: Duration) -> std::io::Result<Self> {
+        Ok(Self {
+            socket: Arc::new(socket2::SockRef::from(stream).try_clone()?),
+            timeout,
+        })
+    }
+
+    /// Watch the body of a response. The connection is shut down once the body is not polled
+    /// for longer than the timeout, which happens when the client stops reading: actix only
+    /// polls bodies while the data already sent to the client does not fill its buffers
+    pub fn watch<S>(&self, body: S) -> WatchedBody<S> {
+        let last_poll = Arc::new(Mutex::new(Instant::now()));
+        actix_web::rt::spawn(in_current_request(watch_body(
+            Arc::downgrade(&last_poll),
+            self.socket.clone(),
+            self.timeout,
+        )));
+
+        WatchedBody {
+            inner: body,
+            pending: Bytes::new(),
+            last_poll,
+        }
+    }
+}
+
+/// Shut the socket down once the body stays idle for longer than `timeout`. Stops when the
+/// body is dropped
+async fn watch_body(last_poll: Weak<Mutex<Instant>>, socket: Arc<socket2::Socket>, timeout: Duration) {
+    loop {
+        actix_web::rt::time::sleep(timeout.min(Duration::from_secs(1))).await;
+
+        let Some(last_poll) = last_poll.upgrade() else {
+            return;
+        };
+        if last_poll.lock().unwrap_or_else(PoisonError::into_inner).elapsed() > timeout {
+            log_error!("Dropping a connection that stopped reading its response");
+            let _ = socket.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+/// Body of a response watched by an [`IdleWatchdog`]
+pub struct WatchedBody<S> {
+    inner: S,
+    /// Part of the last chunk of the inner body that was not sent yet
+    pending: Bytes,
+    last_poll: Arc<Mutex<Instant>>,
+}
+
+impl<S, E> Stream for WatchedBody<S>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        *self.last_poll.lock().unwrap_or_else(PoisonError::into_inner) = Instant::now();
+
+        if self.pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.pending = chunk,
+                other => return other,
+            }
+        }
+
+        let len = self.pending.len().min(WATCHED_CHUNK_SIZE);
+        Poll::Ready(Some(Ok(self.pending.split_to(len))))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (low, high) = self.inner.size_hint();
+        let pending = self.pending.len();
+        (low.saturating_add(pending), high.and_then(|h| h.checked_add(pending)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn bodies_are_sent_in_small_chunks() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let server = TcpStream::from_std(server).unwrap();
+
+        let content = Bytes::from(vec![b'a'; 3 * WATCHED_CHUNK_SIZE / 2]);
+        let watchdog = IdleWatchdog::new(&server, Duration::from_secs(60)).unwrap();
+        let chunks = watchdog
+            .watch(futures::stream::iter([Ok::<_, std::io::Error>(content.clone())]))
+            .map(|c| c.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.concat(), content);
+    }
+
+    #[actix_web::test]
+    async fn idle_bodies_drop_the_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        let server = TcpStream::from_std(server).unwrap();
+
+        let watchdog = IdleWatchdog::new(&server, Duration::from_millis(100)).unwrap();
+        let mut body = watchdog.watch(futures::stream::iter([Ok::<_, std::io::Error>(Bytes::from("a"))]));
+        body.next().await.unwrap().unwrap();
+
+        // The body is not polled anymore, as if the client stopped reading
+        actix_web::rt::time::sleep(Duration::from_secs(2)).await;
+
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut received = vec![];
+        assert_eq!(client.read_to_end(&mut received).unwrap(), 0);
+    }
+}
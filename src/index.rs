@@ -0,0 +1,119 @@
+//! Index of the sizes of the blobs and the images of the registry, maintained
+//! incrementally so that usage queries do not have to walk the storage
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+
+use crate::error::RegistryResult;
+use crate::storage::{get_blob_list, get_docker_images_list, BlobReference, DockerImage};
+use crate::utils::write_file_atomic;
+
+/// Name of the index file, at the root of the registry
+const INDEX_FILE_NAME: &str = "size_index.json";
+
+/// Serializes the updates of the index
+static INDEX_LOCK: Mutex<()> = Mutex::new(());
+
+/// Storage of the size index
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IndexBackend {
+    /// JSON file stored next to the blobs and the repositories
+    Json,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SizeIndex {
+    /// Size of each blob, indexed by digest
+    pub blobs: BTreeMap<String, u64>,
+    /// Total size of the blobs referenced by each image, indexed by image name
+    pub images: BTreeMap<String, u64>,
+}
+
+impl SizeIndex {
+    fn path(storage: &Path) -> PathBuf {
+        storage.join(INDEX_FILE_NAME)
+    }
+
+    /// Load the index of a registry. An empty index is returned if it was never saved
+    pub fn load(storage: &Path) -> RegistryResult<Self> {
+        let path = Self::path(storage);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    /// Save the index of a registry
+    pub fn save(&self, storage: &Path) -> RegistryResult<()> {
+        write_file_atomic(&Self::path(storage), serde_json::to_string(self)?, false)?;
+        Ok(())
+    }
+
+    /// Apply a change to the index of a registry
+    pub fn update<F>(storage: &Path, f: F) -> RegistryResult<()>
+    where
+        F: FnOnce(&mut Self) -> RegistryResult<()>,
+    {
+        let _guard = INDEX_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let mut index = Self::load(storage)?;
+        f(&mut index)?;
+        index.save(storage)
+    }
+
+    /// Build the index of a registry from the content of its storage
+    pub fn rebuild(storage: &Path) -> RegistryResult<Self> {
+        let mut index = Self::default();
+
+        for blob in get_blob_list(storage)? {
+            index.add_blob(&blob, storage)?;
+        }
+
+        for image in get_docker_images_list(storage)? {
+            index.update_image(&DockerImage::new(storage, &image))?;
+        }
+
+        Ok(index)
+    }
+
+    /// Record the size of a stored blob
+    pub fn add_blob(&mut self, blob: &BlobReference, storage: &Path) -> RegistryResult<()> {
+        let size = blob.existing_data_path(storage)?.metadata()?.len();
+        self.blobs.insert(blob.to_digest(), size);
+        Ok(())
+    }
+
+    /// Forget a deleted blob
+    pub fn remove_blob(&mut self, blob: &BlobReference) {
+        self.blobs.remove(&blob.to_digest());
+    }
+
+    /// Compute again the size of an image, after its manifests changed
+    pub fn update_image(&mut self, image: &DockerImage) -> RegistryResult<()> {
+        let blobs = image.referenced_blobs()?;
+
+        if blobs.is_empty() {
+            self.images.remove(&image.image);
+            return Ok(());
+        }
+
+        let size = blobs.iter().filter_map(|b| self.blobs.get(b)).sum();
+        self.images.insert(image.image.to_string(), size);
+        Ok(())
+    }
+
+    /// Forget the blobs removed by the garbage collector
+    pub fn prune(&mut self, storage: &Path) {
+        self.blobs
+            .retain(|digest, _| BlobReference::from_str(digest).is_ok_and(|b| b.data_path(storage).exists()));
+    }
+
+    /// Get the total size of the blobs of the registry
+    pub fn total_size(&self) -> u64 {
+        self.blobs.values().sum()
+    }
+}
@@ -1,7 +1,14 @@
 pub mod api;
 pub mod constants;
 pub mod docker;
+pub mod doctor;
+pub mod error;
+pub mod idle_timeout;
+pub mod index;
 pub mod read_file_stream;
+pub mod request_id;
 pub mod server;
+pub mod state;
 pub mod storage;
+pub mod tls;
 pub mod utils;
@@ -1,100 +1,584 @@
-use std::io::{Error, ErrorKind};
 use std::path::{Path, PathBuf};
 use std::process;
+use std::time::Duration;
 
 use bcrypt::DEFAULT_COST;
 
+use dockerust::constants::{MIN_PASSWORD_LENGTH, NEW_PASSWORD_ENV, NEW_USER_ENV, STALE_UPLOADS_AGE};
+use dockerust::doctor;
+use dockerust::error::RegistryError;
+use dockerust::index::SizeIndex;
 use dockerust::server;
 use dockerust::server::{Credentials, ServerConfig};
-use dockerust::storage::clean_storage;
-use dockerust::utils::{rand_str, request_input};
+use dockerust::state::ServerState;
+use dockerust::storage::clean_stale_uploads;
+use dockerust::utils::{rand_secret, request_input, request_secret};
 
-fn show_usage() {
-    let args = std::env::args().collect::<Vec<_>>();
-    eprintln!("Usage: {} {{init-config|serve|add_user}} [conf_file]", args[0]);
-    process::exit(-1);
+/// Failures that abort the program. Each kind of failure has its own exit code
+#[derive(Debug, thiserror::Error)]
+enum StartupError {
+    #[error("Usage: {0} {{init-config|serve|add_user|print-config|rebuild-index|doctor}} conf_file [user_name] [password] [--force] [--discard-credentials]")]
+    Usage(String),
+    #[error("Specified configuration file does not exists!")]
+    ConfigNotFound,
+    #[error("Specified storage path does not exists!")]
+    StorageNotFound,
+    #[error("Configuration file already exists! Use --force to overwrite it")]
+    ConfigExists,
+    #[error("Configuration file contains credentials! Use --discard-credentials to overwrite them")]
+    ConfigHasCredentials,
+    #[error("Configuration file does not exists!")]
+    ConfigMissing,
+    #[error("Auth tokens lifetime must be greater than zero!")]
+    InvalidTokenTtl,
+    #[error("Invalid immutable tag pattern: {0}")]
+    InvalidTagPattern(String),
+    #[error("Invalid pushable namespace pattern: {0}")]
+    InvalidNamespacePattern(String),
+    #[error("{0}")]
+    InvalidListenAddress(String),
+    #[error("{0}")]
+    InvalidTls(String),
+    #[error("{0}")]
+    InvalidExtraHeader(String),
+    #[error("{0}")]
+    InvalidUploadBuffer(String),
+    #[error("Password must be at least {MIN_PASSWORD_LENGTH} characters long!")]
+    WeakPassword,
+    #[error("{0} problem(s) found in the storage!")]
+    StorageProblems(usize),
+    #[error("Error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl StartupError {
+    /// Get the exit code of the program for this error
+    fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Usage(_) => -1,
+            StartupError::ConfigNotFound => -2,
+            StartupError::StorageNotFound => -3,
+            StartupError::ConfigExists => -4,
+            StartupError::ConfigMissing => -5,
+            StartupError::InvalidTokenTtl => -6,
+            StartupError::InvalidTagPattern(_) => -7,
+            StartupError::InvalidListenAddress(_) => -8,
+            StartupError::WeakPassword => -9,
+            StartupError::ConfigHasCredentials => -10,
+            StartupError::InvalidTls(_) => -11,
+            StartupError::StorageProblems(_) => -12,
+            StartupError::InvalidExtraHeader(_) => -13,
+            StartupError::InvalidNamespacePattern(_) => -14,
+            StartupError::InvalidUploadBuffer(_) => -15,
+            StartupError::Io(_) => 1,
+        }
+    }
+}
+
+impl From<RegistryError> for StartupError {
+    fn from(e: RegistryError) -> Self {
+        StartupError::Io(std::io::Error::other(e.to_string()))
+    }
+}
+
+/// Replacement of the secrets of printed configurations
+const REDACTED: &str = "<redacted>";
+
+type StartupResult<T> = Result<T, StartupError>;
+
+/// Serialization format of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    /// Detect the format of a configuration file from its extension. Defaults to YAML
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => ConfigFormat::Json,
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
+    }
+
+    fn parse(self, content: &str) -> Result<ServerConfig, String> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_json::from_str(content).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::from_str(content).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn serialize(self, conf: &ServerConfig) -> Result<String, String> {
+        match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(conf).map_err(|e| e.to_string()),
+            ConfigFormat::Json => serde_json::to_string_pretty(conf).map_err(|e| e.to_string()),
+            ConfigFormat::Toml => toml::to_string(conf).map_err(|e| e.to_string()),
+        }
+    }
 }
 
-fn init_config(conf_path: &Path) -> std::io::Result<()> {
-    if conf_path.exists() {
-        eprintln!("Configuration file already exists!");
-        process::exit(-4);
+fn read_config(conf_path: &Path) -> StartupResult<ServerConfig> {
+    ConfigFormat::from_path(conf_path)
+        .parse(&std::fs::read_to_string(conf_path)?)
+        .map_err(|e| std::io::Error::other(format!("failed to deserialize config: {}", e)).into())
+}
+
+fn write_config(conf_path: &Path, conf: &ServerConfig) -> StartupResult<()> {
+    std::fs::write(
+        conf_path,
+        ConfigFormat::from_path(conf_path)
+            .serialize(conf)
+            .map_err(|e| std::io::Error::other(format!("failed to serialize config: {}", e)))?,
+    )?;
+    Ok(())
+}
+
+/// Check if an existing configuration file can be replaced by a new one
+fn check_overwrite(conf_path: &Path, force: bool, discard_credentials: bool) -> StartupResult<()> {
+    if !conf_path.exists() {
+        return Ok(());
     }
 
+    if !force {
+        return Err(StartupError::ConfigExists);
+    }
+
+    if !discard_credentials && !read_config(conf_path)?.credentials.is_empty() {
+        return Err(StartupError::ConfigHasCredentials);
+    }
+
+    eprintln!("Warning: {} will be overwritten!", conf_path.display());
+    Ok(())
+}
+
+fn init_config(conf_path: &Path, force: bool, discard_credentials: bool) -> StartupResult<()> {
+    check_overwrite(conf_path, force, discard_credentials)?;
+
     let conf = ServerConfig {
         storage_path: PathBuf::from(request_input("storage path")?),
         listen_address: request_input("listen_address (ex: 127.0.0.1:45654)")?,
         access_url: request_input("access_url")?,
-        app_secret: rand_str(50),
-        credentials: vec![],
+        app_secret: rand_secret(50),
+        ..Default::default()
     };
 
-    std::fs::write(
-        conf_path,
-        serde_yaml::to_string(&conf).map_err(|_| Error::new(ErrorKind::Other, "failed to deserialize"))?,
-    )?;
+    write_config(conf_path, &conf)
+}
 
-    Ok(())
+/// Create the credentials of a new user, checking the strength of its password
+fn new_credentials(user_name: String, password: &str) -> StartupResult<Credentials> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(StartupError::WeakPassword);
+    }
+
+    Ok(Credentials {
+        user_name,
+        password_hash: bcrypt::hash(password, DEFAULT_COST)
+            .map_err(|_| std::io::Error::other("failed to hash password"))?,
+        admin: false,
+    })
+}
+
+/// Get a value from the command line arguments, or else from the environment
+fn arg_or_env(arg: Option<&String>, env: &str) -> Option<String> {
+    arg.cloned().or_else(|| std::env::var(env).ok())
 }
 
-fn add_user(conf_path: &Path) -> std::io::Result<()> {
+fn add_user(conf_path: &Path, user_name: Option<String>, password: Option<String>) -> StartupResult<()> {
     if !conf_path.exists() {
-        eprintln!("Configuration file does not exists!");
-        process::exit(-5);
+        return Err(StartupError::ConfigMissing);
     }
 
-    let mut conf: ServerConfig = serde_yaml::from_str(&std::fs::read_to_string(conf_path)?)
-        .map_err(|_| Error::new(ErrorKind::Other, "failed to deserialize"))?;
+    let mut conf = read_config(conf_path)?;
 
-    conf.credentials.push(Credentials {
-        user_name: request_input("user name")?,
-        password_hash: bcrypt::hash(request_input("password")?, DEFAULT_COST)
-            .map_err(|_| Error::new(ErrorKind::Other, "failed to hash password"))?,
-    });
+    // Interactive prompts are only used for the values that were not provided
+    let user_name = match user_name {
+        Some(u) => u,
+        None => request_input("user name")?,
+    };
+    let password = match password {
+        Some(p) => p,
+        None => request_secret("password")?,
+    };
+    conf.credentials.push(new_credentials(user_name, &password)?);
 
-    std::fs::write(
-        conf_path,
-        serde_yaml::to_string(&conf).map_err(|_| Error::new(ErrorKind::Other, "failed to serialize config"))?,
-    )?;
+    write_config(conf_path, &conf)?;
 
     println!("User added.");
 
     Ok(())
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let args = std::env::args().collect::<Vec<_>>();
-    if args.len() != 3 {
-        show_usage();
+/// Load the configuration of the server, and check it is usable
+fn load_config(conf_path: &Path) -> StartupResult<ServerConfig> {
+    if !conf_path.exists() {
+        return Err(StartupError::ConfigNotFound);
     }
 
-    let conf_path: &Path = args[2].as_ref();
+    let config = read_config(conf_path)?;
 
-    match args[1].as_str() {
-        "serve" => { /* Default usage*/ }
-        "init-config" => init_config(conf_path)?,
-        "add_user" => add_user(conf_path)?,
-        _ => show_usage(),
+    if !config.storage_path.exists() {
+        return Err(StartupError::StorageNotFound);
     }
 
+    config
+        .check_listen_address()
+        .map_err(StartupError::InvalidListenAddress)?;
+
+    config.check_tls().map_err(StartupError::InvalidTls)?;
+    config.check_extra_headers().map_err(StartupError::InvalidExtraHeader)?;
+    config
+        .check_upload_buffer_size()
+        .map_err(StartupError::InvalidUploadBuffer)?;
+
+    if config.token_ttl() == 0 {
+        return Err(StartupError::InvalidTokenTtl);
+    }
+
+    if let Some(p) = config
+        .immutable_tag_patterns
+        .iter()
+        .find(|p| glob::Pattern::new(p).is_err())
+    {
+        return Err(StartupError::InvalidTagPattern(p.to_string()));
+    }
+
+    if let Some(p) = config
+        .pushable_namespaces
+        .iter()
+        .find(|p| glob::Pattern::new(p).is_err())
+    {
+        return Err(StartupError::InvalidNamespacePattern(p.to_string()));
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    if config.stream_idle_timeout_secs.is_some() {
+        eprintln!(
+            "Warning: stream_idle_timeout_secs only drops the clients that stop reading on this system, \
+             not the ones that stop acknowledging the data sent to them"
+        );
+    }
+
+    Ok(config)
+}
+
+/// Diagnose the storage of the registry. The configuration is not checked like for `serve`,
+/// so that a missing storage is reported as a problem
+fn doctor(conf_path: &Path) -> StartupResult<()> {
     if !conf_path.exists() {
-        eprintln!("Specified configuration file does not exists!");
-        process::exit(-2);
+        return Err(StartupError::ConfigNotFound);
+    }
+
+    let config = read_config(conf_path)?;
+    let problems = doctor::diagnose(&config.storage_path, &config.registry_path())?;
+    print!("{}", doctor::report(&problems));
+    if !problems.is_empty() {
+        return Err(StartupError::StorageProblems(problems.len()));
+    }
+    Ok(())
+}
+
+/// Get the configuration `serve` would use, as YAML, with its secrets redacted
+fn effective_config(conf_path: &Path) -> StartupResult<String> {
+    let mut config = load_config(conf_path)?;
+
+    config.app_secret = REDACTED.to_string();
+    for cred in &mut config.credentials {
+        cred.password_hash = REDACTED.to_string();
     }
 
-    let config: ServerConfig = serde_yaml::from_str(&std::fs::read_to_string(conf_path)?)
-        .map_err(|_| Error::new(ErrorKind::Other, "failed to deserialize"))?;
+    ConfigFormat::Yaml
+        .serialize(&config)
+        .map_err(|e| std::io::Error::other(format!("failed to serialize config: {}", e)).into())
+}
 
-    if !config.storage_path.exists() {
-        eprintln!("Specified storage path does not exists!");
-        process::exit(-3);
+async fn run(args: &[String]) -> StartupResult<()> {
+    let max_args = match args.get(1).map(String::as_str) {
+        Some("add_user" | "init-config") => 5,
+        _ => 3,
+    };
+    if args.len() < 3 || args.len() > max_args {
+        return Err(StartupError::Usage(args[0].to_string()));
+    }
+
+    let conf_path: &Path = args[2].as_ref();
+
+    match args[1].as_str() {
+        "serve" => { /* Default usage*/ }
+        "init-config" => {
+            let flags = &args[3..];
+            if flags.iter().any(|f| f != "--force" && f != "--discard-credentials") {
+                return Err(StartupError::Usage(args[0].to_string()));
+            }
+            init_config(
+                conf_path,
+                flags.iter().any(|f| f == "--force"),
+                flags.iter().any(|f| f == "--discard-credentials"),
+            )?
+        }
+        "add_user" => add_user(
+            conf_path,
+            arg_or_env(args.get(3), NEW_USER_ENV),
+            arg_or_env(args.get(4), NEW_PASSWORD_ENV),
+        )?,
+        "rebuild-index" => {
+            let config = load_config(conf_path)?;
+            SizeIndex::rebuild(&config.registry_path())?.save(&config.registry_path())?;
+            println!("Size index rebuilt.");
+            return Ok(());
+        }
+        "print-config" => {
+            print!("{}", effective_config(conf_path)?);
+            return Ok(());
+        }
+        "doctor" => return doctor(conf_path),
+        _ => return Err(StartupError::Usage(args[0].to_string())),
     }
 
+    let config = load_config(conf_path)?;
+
     println!("Cleaning storage...");
-    clean_storage(&config.storage_path).unwrap();
+    let stale_uploads = clean_stale_uploads(
+        &config.registry_path(),
+        config.upload_path.as_deref(),
+        Duration::from_secs(STALE_UPLOADS_AGE),
+    )?;
+    if stale_uploads > 0 {
+        println!("Removed {} stale upload(s)", stale_uploads);
+    }
+
+    let state = ServerState::default();
+    // The server is not listening yet, so no push can be in progress
+    state.run_gc(&config.registry_path(), Duration::ZERO)?;
+    server::update_index(&config, |i| {
+        i.prune(&config.registry_path());
+        Ok(())
+    })?;
 
     println!("Server will start to listen on {}", config.listen_address);
 
-    server::start(config).await
+    Ok(server::start(config, state).await?)
+}
+
+#[actix_web::main]
+async fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+
+    if let Err(e) = run(&args).await {
+        eprintln!("{}", e);
+        process::exit(e.exit_code());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+
+    fn write_test_config(dir: &Path, f: impl FnOnce(&mut ServerConfig)) -> PathBuf {
+        let mut conf = ServerConfig {
+            storage_path: dir.to_path_buf(),
+            listen_address: "127.0.0.1:45654".to_string(),
+            access_url: "http://localhost".to_string(),
+            app_secret: rand_secret(50),
+            ..Default::default()
+        };
+        f(&mut conf);
+
+        let path = dir.join("conf.yaml");
+        write_config(&path, &conf).unwrap();
+        path
+    }
+
+    #[test]
+    fn valid_config_is_loaded() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = write_test_config(&dir, |_| {});
+        assert!(load_config(&path).is_ok());
+    }
+
+    #[test]
+    fn doctor_reports_a_missing_storage() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = write_test_config(&dir, |c| c.storage_path = dir.join("missing"));
+
+        assert_eq!(doctor(&path).err().unwrap().exit_code(), -12);
+        assert_eq!(doctor(&dir.join("missing.yaml")).err().unwrap().exit_code(), -2);
+    }
+
+    #[test]
+    fn invalid_configs_have_stable_exit_codes() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+
+        let err = load_config(&dir.join("missing.yaml")).err().unwrap();
+        assert_eq!(err.exit_code(), -2);
+
+        let path = write_test_config(&dir, |c| c.storage_path = dir.join("missing"));
+        assert_eq!(load_config(&path).err().unwrap().exit_code(), -3);
+
+        let path = write_test_config(&dir, |c| c.listen_address = "127.0.0.1;45654".to_string());
+        assert_eq!(load_config(&path).err().unwrap().exit_code(), -8);
+
+        let path = write_test_config(&dir, |c| c.token_ttl_secs = Some(0));
+        assert_eq!(load_config(&path).err().unwrap().exit_code(), -6);
+
+        let path = write_test_config(&dir, |c| c.immutable_tag_patterns = vec!["[".to_string()]);
+        assert_eq!(load_config(&path).err().unwrap().exit_code(), -7);
+
+        let path = write_test_config(&dir, |c| c.pushable_namespaces = vec!["team-[".to_string()]);
+        assert_eq!(load_config(&path).err().unwrap().exit_code(), -14);
+
+        for size in [0, 1024 * 1024 * 1024] {
+            let path = write_test_config(&dir, |c| c.upload_buffer_bytes = Some(size));
+            assert_eq!(load_config(&path).err().unwrap().exit_code(), -15);
+        }
+
+        assert_eq!(init_config(&path, false, false).err().unwrap().exit_code(), -4);
+        assert_eq!(
+            add_user(&dir.join("missing.yaml"), None, None).unwrap_err().exit_code(),
+            -5
+        );
+    }
+
+    #[test]
+    fn connection_limits_are_loaded() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = write_test_config(&dir, |c| {
+            c.workers = NonZeroUsize::new(2);
+            c.max_connections = NonZeroUsize::new(100);
+        });
+
+        let conf = load_config(&path).unwrap();
+        assert_eq!(conf.workers.map(NonZeroUsize::get), Some(2));
+        assert_eq!(conf.max_connections.map(NonZeroUsize::get), Some(100));
+
+        // Zero workers would make the server panic on startup
+        let content = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("workers: 2", "workers: 0");
+        std::fs::write(&path, content).unwrap();
+        assert!(load_config(&path).is_err());
+    }
+
+    #[test]
+    fn empty_catalog_pages_are_rejected() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = write_test_config(&dir, |c| c.catalog_max_page_size = NonZeroUsize::new(5));
+        assert_eq!(
+            load_config(&path).unwrap().catalog_max_page_size.map(NonZeroUsize::get),
+            Some(5)
+        );
+
+        // An empty page would never let clients go through the catalog
+        let content = std::fs::read_to_string(&path)
+            .unwrap()
+            .replace("catalog_max_page_size: 5", "catalog_max_page_size: 0");
+        std::fs::write(&path, content).unwrap();
+        assert!(load_config(&path).is_err());
+    }
+
+    #[test]
+    fn weak_passwords_are_rejected() {
+        for password in ["", "short"] {
+            let err = new_credentials("user".to_string(), password).err().unwrap();
+            assert_eq!(err.exit_code(), -9);
+        }
+
+        let creds = new_credentials("user".to_string(), "long enough password").unwrap();
+        assert!(bcrypt::verify("long enough password", &creds.password_hash).unwrap());
+    }
+
+    #[test]
+    fn add_user_non_interactively() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = write_test_config(&dir, |_| {});
+
+        add_user(
+            &path,
+            Some("user".to_string()),
+            Some("long enough password".to_string()),
+        )
+        .unwrap();
+
+        let conf = read_config(&path).unwrap();
+        assert!(conf.check_auth("user", "long enough password"));
+        assert!(!conf.check_auth("user", "bad password"));
+    }
+
+    #[test]
+    fn config_round_trips_through_every_format() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let conf = read_config(&write_test_config(&dir, |c| {
+            c.token_ttl_secs = Some(60);
+            c.immutable_tag_patterns = vec!["v*".to_string()];
+            c.credentials = vec![new_credentials("user".to_string(), "long enough password").unwrap()];
+        }))
+        .unwrap();
+
+        for (name, format) in [
+            ("conf.yml", ConfigFormat::Yaml),
+            ("conf.json", ConfigFormat::Json),
+            ("conf.toml", ConfigFormat::Toml),
+            ("conf.cfg", ConfigFormat::Yaml),
+        ] {
+            let path = dir.join(name);
+            assert_eq!(ConfigFormat::from_path(&path), format);
+
+            write_config(&path, &conf).unwrap();
+            let read = read_config(&path).unwrap();
+            assert_eq!(
+                serde_json::to_value(&read).unwrap(),
+                serde_json::to_value(&conf).unwrap(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn printed_config_is_redacted() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let creds = new_credentials("user".to_string(), "long enough password").unwrap();
+        let hash = creds.password_hash.clone();
+        let path = write_test_config(&dir, |c| {
+            c.app_secret = "super-secret-value".to_string();
+            c.credentials = vec![creds];
+        });
+
+        let printed = effective_config(&path).unwrap();
+        assert!(!printed.contains("super-secret-value"));
+        assert!(!printed.contains(&hash));
+        assert!(printed.contains("user"));
+
+        let conf: ServerConfig = serde_yaml::from_str(&printed).unwrap();
+        assert_eq!(conf.app_secret, REDACTED);
+        assert_eq!(conf.credentials[0].password_hash, REDACTED);
+    }
+
+    #[test]
+    fn existing_config_is_only_overwritten_when_forced() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = write_test_config(&dir, |_| {});
+
+        assert_eq!(check_overwrite(&path, false, false).err().unwrap().exit_code(), -4);
+        check_overwrite(&path, true, false).unwrap();
+
+        // Credentials are not discarded without confirmation
+        let path = write_test_config(&dir, |c| {
+            c.credentials = vec![Credentials {
+                user_name: "user".to_string(),
+                password_hash: "hash".to_string(),
+                admin: false,
+            }]
+        });
+        assert_eq!(check_overwrite(&path, true, false).err().unwrap().exit_code(), -10);
+        check_overwrite(&path, true, true).unwrap();
+
+        check_overwrite(&dir.join("new.yaml"), false, false).unwrap();
+    }
 }
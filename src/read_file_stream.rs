@@ -6,6 +6,9 @@ use std::task::{Context, Poll};
 use actix_web::web::Bytes;
 use futures::Stream;
 
+use crate::log_error;
+use crate::request_id;
+
 const CHUNK_SIZE: u64 = 1024 * 1024 * 50; // 50 MB
 
 pub struct ReadFileStream {
@@ -13,6 +16,8 @@ pub struct ReadFileStream {
     processed: usize,
     file: std::fs::File,
     error: bool,
+    /// Request the file is streamed for, as the stream is polled once it was answered
+    request_id: Option<String>,
 }
 
 impl ReadFileStream {
@@ -22,8 +27,15 @@ impl ReadFileStream {
             processed: 0,
             file: std::fs::File::open(path)?,
             error: false,
+            request_id: request_id::current(),
         })
     }
+
+    /// Number of bytes left to stream. The stream ends early if the file was truncated since
+    /// it was created
+    fn remaining(&self) -> u64 {
+        self.file_size.saturating_sub(self.processed as u64)
+    }
 }
 
 impl Stream for ReadFileStream {
@@ -34,12 +46,14 @@ impl Stream for ReadFileStream {
             return Poll::Ready(None);
         }
 
-        let mut chunk = Vec::with_capacity(CHUNK_SIZE as usize);
-        let size = self.file.by_ref().take(CHUNK_SIZE).read_to_end(&mut chunk);
+        // Never stream more than the announced size, even if the file grew
+        let limit = CHUNK_SIZE.min(self.remaining());
+        let mut chunk = Vec::with_capacity(limit as usize);
+        let size = self.file.by_ref().take(limit).read_to_end(&mut chunk);
 
         let size = match size {
             Err(e) => {
-                eprintln!("Failed to read from file! {}", e);
+                request_id::in_request(&self.request_id, || log_error!("Failed to read from file! {}", e));
                 self.error = true;
                 return Poll::Ready(Some(Err(actix_web::Error::from(e))));
             }
@@ -56,6 +70,65 @@ impl Stream for ReadFileStream {
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.file_size as usize - self.processed, None)
+        let remaining = self.remaining() as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// Stream a file, checking that the size hints decrease down to zero
+    fn stream_file(path: &Path) -> Vec<u8> {
+        let mut stream = ReadFileStream::new(path).unwrap();
+        let mut content = vec![];
+        let mut last_hint = stream.size_hint();
+
+        while let Some(chunk) = block_on(stream.next()) {
+            content.extend_from_slice(&chunk.unwrap());
+
+            let hint = stream.size_hint();
+            assert!(hint.0 < last_hint.0, "{:?} >= {:?}", hint, last_hint);
+            last_hint = hint;
+        }
+        assert_eq!(last_hint, (0, Some(0)));
+
+        content
+    }
+
+    #[test]
+    fn files_are_streamed() {
+        let file = mktemp::Temp::new_file().unwrap();
+        std::fs::write(&file, "hello").unwrap();
+
+        let stream = ReadFileStream::new(&file).unwrap();
+        assert_eq!(stream.size_hint(), (5, Some(5)));
+
+        assert_eq!(stream_file(&file), b"hello");
+    }
+
+    #[test]
+    fn resized_files_do_not_break_streams() {
+        let file = mktemp::Temp::new_file().unwrap();
+        std::fs::write(&file, "hello").unwrap();
+
+        // Grown files are streamed up to their initial size
+        let mut stream = ReadFileStream::new(&file).unwrap();
+        std::fs::write(&file, "hello world").unwrap();
+        let chunk = block_on(stream.next()).unwrap().unwrap();
+        assert_eq!(chunk, "hello");
+        assert_eq!(stream.size_hint(), (0, Some(0)));
+        assert!(block_on(stream.next()).is_none());
+
+        // Truncated files end the stream early
+        let mut stream = ReadFileStream::new(&file).unwrap();
+        std::fs::write(&file, "hi").unwrap();
+        assert_eq!(block_on(stream.next()).unwrap().unwrap(), "hi");
+        assert!(block_on(stream.next()).is_none());
+        assert_eq!(stream.size_hint(), (9, Some(9)));
     }
 }
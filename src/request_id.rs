@@ -0,0 +1,194 @@
+//! Correlation of requests with logs and error responses
+
+use std::cell::RefCell;
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::web::Data;
+use actix_web::{HttpMessage, HttpRequest};
+use uuid::Uuid;
+
+use crate::server::ServerConfig;
+
+/// Header carrying the ID of a request
+pub const REQUEST_ID_HEADER: &str = "X-Request-Id";
+
+/// ID of a request, provided by the client or generated
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// Get the ID of a request, if the middleware was applied to it
+    pub fn of(req: &HttpRequest) -> Option<String> {
+        req.extensions().get::<RequestId>().map(|r| r.0.clone())
+    }
+}
+
+thread_local! {
+    /// ID of the request being processed by the current thread
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Get the ID of the request being processed, to correlate logs with it
+pub fn current() -> Option<String> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// Run `f` as part of the processing of a request, e.g. to log with its ID
+pub fn in_request<R>(id: &Option<String>, f: impl FnOnce() -> R) -> R {
+    let previous = CURRENT.with(|c| c.replace(id.clone()));
+    let res = f();
+    CURRENT.with(|c| *c.borrow_mut() = previous);
+    res
+}
+
+/// Future processing a request. The workers interleave the processing of requests, so the
+/// ID is only current while the future is polled
+pub struct WithRequestId<F> {
+    id: Option<String>,
+    inner: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        in_request(&this.id, || this.inner.as_mut().poll(cx))
+    }
+}
+
+/// Keep the ID of the current request while `fut` runs, e.g. in a spawned task
+pub fn in_current_request<F: Future>(fut: F) -> WithRequestId<F> {
+    WithRequestId {
+        id: current(),
+        inner: Box::pin(fut),
+    }
+}
+
+/// Print a line to the standard output, prefixed with the ID of the current request
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        match $crate::request_id::current() {
+            Some(id) => println!("[{}] {}", id, format_args!($($arg)*)),
+            None => println!($($arg)*),
+        }
+    };
+}
+
+/// Print a line to the standard error, prefixed with the ID of the current request
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        match $crate::request_id::current() {
+            Some(id) => eprintln!("[{}] {}", id, format_args!($($arg)*)),
+            None => eprintln!($($arg)*),
+        }
+    };
+}
+
+/// Middleware assigning an ID to each request
+pub struct RequestIdMiddleware;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Transform = RequestIdService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdService { service }))
+    }
+}
+
+pub struct RequestIdService<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|h| h.to_str().ok())
+            .filter(|h| !h.is_empty())
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        let client = req
+            .app_data::<Data<ServerConfig>>()
+            .and_then(|c| c.client_ip(req.request()))
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        // Handlers may start processing the request as soon as they are called
+        let current = Some(id.clone());
+        let fut = in_request(&current, || self.service.call(req));
+        let fut = WithRequestId {
+            id: current,
+            inner: Box::pin(fut),
+        };
+
+        Box::pin(async move {
+            let mut res = fut.await?.map_into_boxed_body();
+
+            if res.status().is_server_error() {
+                match res.response().error() {
+                    Some(e) => eprintln!("[{}] [{}] Internal error while processing request! {}", id, client, e),
+                    None => eprintln!("[{}] [{}] Request failed with status {}", id, client, res.status()),
+                }
+                res = res.map_body(|_, body| add_request_id_to_errors(body, &id));
+            }
+
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                res.headers_mut().insert(HeaderName::from_static("x-request-id"), value);
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+/// Add the ID of the request to the details of the errors of a Docker error response
+fn add_request_id_to_errors(body: BoxBody, id: &str) -> BoxBody {
+    let bytes = match body.try_into_bytes() {
+        Ok(bytes) => bytes,
+        Err(body) => return body,
+    };
+
+    let mut json: serde_json::Value = match serde_json::from_slice(&bytes) {
+        Ok(json) => json,
+        Err(_) => return BoxBody::new(bytes),
+    };
+
+    let Some(errors) = json.get_mut("errors").and_then(|e| e.as_array_mut()) else {
+        return BoxBody::new(bytes);
+    };
+
+    for detail in errors.iter_mut().filter_map(|e| e.get_mut("detail")?.as_object_mut()) {
+        detail.insert("request_id".to_string(), id.into());
+    }
+
+    BoxBody::new(json.to_string())
+}
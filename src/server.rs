@@ -1,31 +1,76 @@
-use actix_web::body::SizedStream;
+use actix_web::body::{BodySize, MessageBody, SizedStream};
+use actix_web::dev::Extensions;
+use actix_web::error::QueryPayloadError;
+use actix_web::http::header::{HeaderName, HeaderValue};
 use actix_web::http::Method;
+use actix_web::middleware::DefaultHeaders;
+use actix_web::rt::task::JoinHandle;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer};
 use base64::{engine::general_purpose as b64decoder, Engine as _};
 use futures::StreamExt;
+use ipnet::IpNet;
 use jsonwebtoken::{encode, Validation};
 use regex::Regex;
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
 use std::cmp::min;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::error::Error;
 use std::fs::OpenOptions;
-use std::io::{ErrorKind, Write};
+use std::io::{BufWriter, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::{LazyLock, PoisonError};
+use std::time::Duration;
 use uuid::Uuid;
 
-use crate::api::{DockerCatalog, DockerErrorMessageType, DockerErrorResponse, DockerTagsList};
-use crate::constants::AUTH_TOKENS_DURATION;
-use crate::docker::DockerManifestOrManifestList;
+use crate::api::{
+    DockerBlobReferrers, DockerCatalog, DockerCatalogEntry, DockerErrorMessageType, DockerErrorResponse,
+    DockerExtendedCatalog, DockerManifestRevision, DockerManifestRevisions, DockerReferrer, DockerReferrersIndex,
+    DockerTagDigest, DockerTagsList, RegistryStatus, VersionInfo,
+};
+use crate::constants::{
+    AUTH_TOKENS_DURATION, DEFAULT_STORAGE_BASE_PATH, GC_GRACE_PERIOD, MAX_BODY_SIZE, MAX_INDEX_MANIFESTS,
+    MAX_MANIFEST_LAYERS, MAX_UPLOAD_BUFFER_SIZE, MIN_UPLOAD_BUFFER_SIZE, REFRESH_TOKENS_DURATION, SMALL_BLOB_SIZE,
+    UPLOADS_IDLE_TIMEOUT, UPLOADS_RETRY_AFTER, UPLOAD_BUFFER_SIZE,
+};
+use crate::docker::{
+    DockerManifestOrManifestList, DOCKER_MANIFEST_V1, DOCKER_MANIFEST_V1_SIGNED, MANIFEST_MEDIA_TYPES,
+    OCI_IMAGE_INDEX_V1,
+};
+use crate::error::{RegistryError, RegistryResult};
+use crate::idle_timeout::IdleWatchdog;
+use crate::index::{IndexBackend, SizeIndex};
+use crate::log_error;
 use crate::read_file_stream::ReadFileStream;
-use crate::storage::{clean_storage, get_docker_images_list, BlobReference, DockerImage};
-use crate::utils::{create_empty_file, sha256sum, sha256sum_str, time};
+use crate::request_id::RequestIdMiddleware;
+use crate::state::{GcStatus, ServerState};
+use crate::storage::{
+    check_image_name, check_tag_name, commit_blob, get_blob_list, get_docker_images_list, is_blob_intact,
+    is_blob_useless, link_manifest, manifest_referenced_blobs, BlobReference, DockerImage,
+};
+use crate::tls::{self, ClientIdentity};
+use crate::utils::{create_empty_file, sha256sum, sha512sum, time};
+
+/// Header advertising the version of the registry API
+const API_VERSION_HEADER: (&str, &str) = ("Docker-Distribution-API-Version", "registry/2.0");
+
+/// Header identifying an upload session, sent on every response about an upload in progress
+const UPLOAD_UUID_HEADER: &str = "Docker-Upload-UUID";
+
+/// Grammar of the UUID of an upload
+static UPLOAD_UUID: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[0-9a-zA-Z\-]+$").unwrap());
 
 #[derive(Clone, serde::Deserialize, serde::Serialize)]
 pub struct Credentials {
     pub user_name: String,
     pub password_hash: String,
+    /// Allow the user to administer the registry, e.g. to delete whole repositories
+    #[serde(default)]
+    pub admin: bool,
 }
 
 #[derive(Clone, serde::Deserialize, serde::Serialize)]
@@ -34,7 +79,179 @@ pub struct ServerConfig {
     pub listen_address: String,
     pub access_url: String,
     pub app_secret: String,
+    /// Retired secrets, still accepted to validate tokens issued before a rotation
+    #[serde(default)]
+    pub previous_app_secrets: Vec<String>,
     pub credentials: Vec<Credentials>,
+    /// Auth tokens lifetime, in seconds. Defaults to [`AUTH_TOKENS_DURATION`]
+    #[serde(default)]
+    pub token_ttl_secs: Option<u64>,
+    /// Path of the registry inside the storage directory. Defaults to [`DEFAULT_STORAGE_BASE_PATH`]
+    #[serde(default)]
+    pub storage_base_path: Option<PathBuf>,
+    /// Maximum size of buffered request bodies, in bytes. Streamed uploads are not
+    /// affected. Defaults to [`MAX_BODY_SIZE`]
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+    /// Forbid pushing a manifest to an existing tag
+    #[serde(default)]
+    pub immutable_tags: bool,
+    /// Glob patterns of the tags that can not be overwritten, when `immutable_tags` is disabled
+    #[serde(default)]
+    pub immutable_tag_patterns: Vec<String>,
+    /// Maximum number of layers of pushed image manifests. Defaults to [`MAX_MANIFEST_LAYERS`]
+    #[serde(default)]
+    pub max_manifest_layers: Option<usize>,
+    /// Maximum number of manifests referenced by pushed manifest lists and image indexes.
+    /// Defaults to [`MAX_INDEX_MANIFESTS`]
+    #[serde(default)]
+    pub max_index_manifests: Option<usize>,
+    /// Maximum number of blob uploads in progress
+    #[serde(default)]
+    pub max_concurrent_uploads: Option<usize>,
+    /// Maximum number of blob uploads in progress for a single user
+    #[serde(default)]
+    pub max_concurrent_uploads_per_user: Option<usize>,
+    /// Allow deleting the blobs that are not referenced by any manifest
+    #[serde(default)]
+    pub enable_delete: bool,
+    /// Maintain an index of the sizes of the blobs and the images, used by `/v2/_usage`
+    #[serde(default)]
+    pub index_backend: Option<IndexBackend>,
+    /// Trust the `X-Forwarded-Proto` and `X-Forwarded-Host` headers set by a reverse proxy
+    /// to build the URLs sent to clients. Only the headers of the `trusted_proxies` are used
+    #[serde(default)]
+    pub trust_forwarded_headers: bool,
+    /// Networks of the reverse proxies allowed to report the address of clients with the
+    /// `X-Forwarded-For` and `Forwarded` headers
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Run the garbage collector in the background at this interval, in seconds
+    #[serde(default)]
+    pub gc_interval_secs: Option<NonZeroU64>,
+    /// Maximum total size of the blobs referenced by a repository, in bytes
+    #[serde(default)]
+    pub repo_quota_bytes: Option<u64>,
+    /// Directory storing the uploads in progress, instead of the directories of the images
+    #[serde(default)]
+    pub upload_path: Option<PathBuf>,
+    /// Directory of the transient scratch files, like the manifests being pushed. Defaults to
+    /// the temporary directory of the system
+    #[serde(default)]
+    pub temp_dir: Option<PathBuf>,
+    /// Maximum number of repositories returned by a page of the catalog
+    #[serde(default)]
+    pub catalog_max_page_size: Option<NonZeroUsize>,
+    /// Number of worker threads serving requests. Defaults to the number of CPUs
+    #[serde(default)]
+    pub workers: Option<NonZeroUsize>,
+    /// Maximum number of concurrent connections handled by each worker. Defaults to 25k
+    #[serde(default)]
+    pub max_connections: Option<NonZeroUsize>,
+    /// PEM certificate chain served over TLS. TLS is enabled when it is set with `tls_key_path`
+    #[serde(default)]
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key of the TLS certificate
+    #[serde(default)]
+    pub tls_key_path: Option<PathBuf>,
+    /// PEM certificates of the authorities issuing client certificates. When set, only the
+    /// clients presenting a certificate they issued are allowed to push
+    #[serde(default)]
+    pub client_ca_path: Option<PathBuf>,
+    /// Time after which the connections of clients that stopped reading a response, or
+    /// acknowledging its data, are dropped
+    #[serde(default)]
+    pub stream_idle_timeout_secs: Option<NonZeroU64>,
+    /// Answer token requests without credentials with a 401 instead of an anonymous token,
+    /// on registries requiring authentication. Anonymous tokens only allow pulls
+    #[serde(default)]
+    pub disable_anonymous_tokens: bool,
+    /// Service advertised to the clients requesting tokens, and audience of the tokens.
+    /// Defaults to the host of `access_url`
+    #[serde(default)]
+    pub token_service: Option<String>,
+    /// Repeat the token in the legacy `token` field of the token responses, next to the
+    /// standard `access_token`, as Docker clients expect
+    #[serde(default = "default_legacy_token_field")]
+    pub legacy_token_field: bool,
+    /// Headers added to every response, unless the response already sets them
+    #[serde(default)]
+    pub extra_headers: BTreeMap<String, String>,
+    /// Glob patterns of the repositories that can be pushed to. Any repository can be pushed to
+    /// when empty
+    #[serde(default)]
+    pub pushable_namespaces: Vec<String>,
+    /// Size of the buffer blob uploads are written through, in bytes. Defaults to
+    /// [`UPLOAD_BUFFER_SIZE`]
+    #[serde(default)]
+    pub upload_buffer_bytes: Option<usize>,
+    /// Time after which uploads without activity are cancelled, in seconds. Defaults to
+    /// [`UPLOADS_IDLE_TIMEOUT`]
+    #[serde(default)]
+    pub upload_idle_timeout_secs: Option<u64>,
+    /// Age under which the garbage collector keeps unreferenced blobs, in seconds. Defaults to
+    /// [`GC_GRACE_PERIOD`]
+    #[serde(default)]
+    pub gc_grace_period_secs: Option<u64>,
+    /// Flush blobs and manifests to the disk before acknowledging pushes
+    #[serde(default = "default_durable_writes")]
+    pub durable_writes: bool,
+}
+
+/// Settings of a registry not configured at all. The storage, addresses and secret are left
+/// empty, to be filled by the caller
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            storage_path: PathBuf::new(),
+            listen_address: String::new(),
+            access_url: String::new(),
+            app_secret: String::new(),
+            previous_app_secrets: vec![],
+            credentials: vec![],
+            token_ttl_secs: None,
+            storage_base_path: None,
+            max_body_size: None,
+            immutable_tags: false,
+            immutable_tag_patterns: vec![],
+            max_manifest_layers: None,
+            max_index_manifests: None,
+            max_concurrent_uploads: None,
+            max_concurrent_uploads_per_user: None,
+            enable_delete: false,
+            index_backend: None,
+            trust_forwarded_headers: false,
+            trusted_proxies: vec![],
+            gc_interval_secs: None,
+            repo_quota_bytes: None,
+            catalog_max_page_size: None,
+            upload_path: None,
+            temp_dir: None,
+            workers: None,
+            max_connections: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            client_ca_path: None,
+            stream_idle_timeout_secs: None,
+            disable_anonymous_tokens: false,
+            token_service: None,
+            legacy_token_field: default_legacy_token_field(),
+            extra_headers: BTreeMap::new(),
+            pushable_namespaces: vec![],
+            upload_buffer_bytes: None,
+            upload_idle_timeout_secs: None,
+            gc_grace_period_secs: None,
+            durable_writes: default_durable_writes(),
+        }
+    }
+}
+
+fn default_durable_writes() -> bool {
+    true
+}
+
+fn default_legacy_token_field() -> bool {
+    true
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
@@ -44,22 +261,273 @@ struct InvalidAuthResponse {
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct AuthResponse {
-    token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
     access_token: String,
     expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
 }
 
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 struct JWTClaims {
+    iss: String,
+    aud: String,
     user: Option<String>,
+    scope: Option<String>,
     timeout: u64,
 }
 
+/// Long-lived token that can be exchanged for new auth tokens
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct RefreshClaims {
+    iss: String,
+    aud: String,
+    user: String,
+    timeout: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenRequest {
+    service: Option<String>,
+    /// Requested scopes. Clients repeat the parameter to request several of them
+    #[serde(skip)]
+    scope: Vec<String>,
+    account: Option<String>,
+    offline_token: Option<bool>,
+}
+
+impl TokenRequest {
+    fn from_query(query: &str) -> Result<Self, QueryPayloadError> {
+        let mut req = web::Query::<Self>::from_query(query)?.into_inner();
+        req.scope = web::Query::<Vec<(String, String)>>::from_query(query)?
+            .into_inner()
+            .into_iter()
+            .filter(|(k, _)| k == "scope")
+            .map(|(_, v)| v)
+            .collect();
+        Ok(req)
+    }
+}
+
+/// OAuth2 form submitted to the token endpoint
+#[derive(Default, serde::Deserialize)]
+struct TokenForm {
+    grant_type: Option<String>,
+    refresh_token: Option<String>,
+    service: Option<String>,
+    scope: Option<String>,
+}
+
 impl ServerConfig {
+    /// Build the configuration of a registry stored in `storage_path`, with the default
+    /// settings, a random secret and no credentials
+    #[cfg(test)]
+    pub fn for_test(storage_path: &std::path::Path) -> Self {
+        Self {
+            storage_path: storage_path.to_path_buf(),
+            listen_address: "127.0.0.1:0".to_string(),
+            access_url: "http://localhost".to_string(),
+            app_secret: crate::utils::rand_str(50),
+            ..Self::default()
+        }
+    }
+
     pub fn need_auth(&self) -> bool {
         !self.credentials.is_empty()
     }
 
+    /// Check if an existing tag can not be overwritten
+    pub fn is_immutable_tag(&self, tag: &str) -> bool {
+        self.immutable_tags
+            || self
+                .immutable_tag_patterns
+                .iter()
+                .any(|p| glob::Pattern::new(p).is_ok_and(|p| p.matches(tag)))
+    }
+
+    /// Check whether a repository can be pushed to
+    pub fn is_pushable(&self, image: &str) -> bool {
+        self.pushable_namespaces.is_empty()
+            || self
+                .pushable_namespaces
+                .iter()
+                .any(|p| glob::Pattern::new(p).is_ok_and(|p| p.matches(image)))
+    }
+
+    /// Get the root of the registry storage
+    pub fn registry_path(&self) -> PathBuf {
+        match &self.storage_base_path {
+            Some(p) => self.storage_path.join(p),
+            None => self.storage_path.join(DEFAULT_STORAGE_BASE_PATH),
+        }
+    }
+
+    /// Get the maximum size of buffered request bodies
+    pub fn max_body_size(&self) -> usize {
+        self.max_body_size.unwrap_or(MAX_BODY_SIZE)
+    }
+
+    /// Get the size of the buffer blob uploads are written through
+    pub fn upload_buffer_size(&self) -> usize {
+        self.upload_buffer_bytes.unwrap_or(UPLOAD_BUFFER_SIZE)
+    }
+
+    /// Get the age under which unreferenced blobs are kept by the garbage collector
+    pub fn gc_grace_period(&self) -> Duration {
+        Duration::from_secs(self.gc_grace_period_secs.unwrap_or(GC_GRACE_PERIOD))
+    }
+
+    /// Get the time after which uploads without activity are cancelled
+    pub fn upload_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.upload_idle_timeout_secs.unwrap_or(UPLOADS_IDLE_TIMEOUT))
+    }
+
+    /// Check that the upload buffer size is within [`MIN_UPLOAD_BUFFER_SIZE`] and
+    /// [`MAX_UPLOAD_BUFFER_SIZE`]
+    pub fn check_upload_buffer_size(&self) -> Result<(), String> {
+        let size = self.upload_buffer_size();
+        if !(MIN_UPLOAD_BUFFER_SIZE..=MAX_UPLOAD_BUFFER_SIZE).contains(&size) {
+            return Err(format!(
+                "upload_buffer_bytes must be between {} and {}",
+                MIN_UPLOAD_BUFFER_SIZE, MAX_UPLOAD_BUFFER_SIZE
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check that a manifest does not exceed the configured limits
+    pub fn manifest_within_limits(&self, manifest: &DockerManifestOrManifestList) -> bool {
+        manifest.layers.as_ref().map_or(0, Vec::len) <= self.max_manifest_layers.unwrap_or(MAX_MANIFEST_LAYERS)
+            && manifest.manifests.as_ref().map_or(0, Vec::len)
+                <= self.max_index_manifests.unwrap_or(MAX_INDEX_MANIFESTS)
+    }
+
+    /// Get the directory of the transient scratch files
+    pub fn temp_path(&self) -> PathBuf {
+        match &self.temp_dir {
+            Some(dir) => dir.to_path_buf(),
+            None => std::env::temp_dir(),
+        }
+    }
+
+    /// Check whether adding blobs, given by digest and size, to an image would exceed the
+    /// repository quota
+    pub fn exceeds_quota(
+        &self,
+        image: &DockerImage,
+        blobs: impl IntoIterator<Item = (String, u64)>,
+    ) -> RegistryResult<bool> {
+        let Some(quota) = self.repo_quota_bytes else {
+            return Ok(false);
+        };
+
+        // Blobs already referenced by the image do not take more space
+        let referenced = image.referenced_blobs()?;
+        let added = blobs
+            .into_iter()
+            .filter(|(digest, _)| !referenced.contains(digest))
+            .collect::<BTreeMap<_, _>>();
+
+        Ok(image.referenced_size()?.saturating_add(added.values().sum()) > quota)
+    }
+
+    /// Get the lifetime of issued auth tokens
+    pub fn token_ttl(&self) -> u64 {
+        self.token_ttl_secs.unwrap_or(AUTH_TOKENS_DURATION)
+    }
+
+    /// Check that the listen address is a valid `host:port` pair
+    pub fn check_listen_address(&self) -> Result<(), String> {
+        let addr = self.listen_address.as_str();
+        if addr.parse::<SocketAddr>().is_ok() {
+            return Ok(());
+        }
+
+        match addr.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() && addr.to_socket_addrs().is_ok() => {
+                Ok(())
+            }
+            _ => Err(format!(
+                "Invalid listen address '{}', expected <ip>:<port> or <host>:<port> (ex: 127.0.0.1:45654)",
+                addr
+            )),
+        }
+    }
+
+    /// Check that the TLS settings are consistent and that their files can be loaded
+    pub fn check_tls(&self) -> Result<(), String> {
+        if self.tls_cert_path.is_some() != self.tls_key_path.is_some() {
+            return Err("tls_cert_path and tls_key_path must be set together".to_string());
+        }
+
+        if self.client_ca_path.is_some() && self.tls_cert_path.is_none() {
+            return Err("client_ca_path requires TLS to be enabled".to_string());
+        }
+
+        tls::server_config(self)
+            .map(|_| ())
+            .map_err(|e| format!("Invalid TLS configuration: {}", e))
+    }
+
+    /// Check that the extra response headers have valid names and values
+    pub fn check_extra_headers(&self) -> Result<(), String> {
+        for (name, value) in &self.extra_headers {
+            HeaderName::from_str(name).map_err(|_| format!("Invalid extra header name '{}'", name))?;
+            HeaderValue::from_str(value).map_err(|_| format!("Invalid value of extra header '{}'", name))?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the middleware adding the extra headers to the responses. Invalid headers are
+    /// ignored, see [`Self::check_extra_headers`]
+    pub fn extra_headers_middleware(&self) -> DefaultHeaders {
+        self.extra_headers
+            .iter()
+            .filter_map(|(name, value)| Some((HeaderName::from_str(name).ok()?, HeaderValue::from_str(value).ok()?)))
+            .fold(DefaultHeaders::new(), DefaultHeaders::add)
+    }
+
+    fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|n| n.contains(ip))
+    }
+
+    /// Get the address of the client of a request. Forwarding headers are only used when
+    /// the request comes from a trusted proxy, so that clients can not forge them
+    pub fn client_ip(&self, req: &HttpRequest) -> Option<IpAddr> {
+        let peer = req.peer_addr()?.ip();
+        if !self.is_trusted_proxy(&peer) {
+            return Some(peer);
+        }
+
+        let mut forwarded = vec![];
+        for h in req.headers().get_all("forwarded").filter_map(|h| h.to_str().ok()) {
+            forwarded.extend(
+                h.split([',', ';'])
+                    .filter_map(|p| p.trim().strip_prefix("for="))
+                    .filter_map(parse_forwarded_ip),
+            );
+        }
+        if forwarded.is_empty() {
+            for h in req.headers().get_all("x-forwarded-for").filter_map(|h| h.to_str().ok()) {
+                forwarded.extend(h.split(',').filter_map(parse_forwarded_ip));
+            }
+        }
+
+        // Each proxy appends the address of its peer, the client is the last address
+        // added by a trusted proxy
+        let mut client = peer;
+        for ip in forwarded.into_iter().rev() {
+            client = ip;
+            if !self.is_trusted_proxy(&ip) {
+                break;
+            }
+        }
+        Some(client)
+    }
+
     fn get_encoding_secret(&self) -> jsonwebtoken::EncodingKey {
         jsonwebtoken::EncodingKey::from_secret(self.app_secret.as_ref())
     }
@@ -68,17 +536,74 @@ impl ServerConfig {
         jsonwebtoken::DecodingKey::from_secret(self.app_secret.as_ref())
     }
 
+    /// Decode a token signed with the current secret or with one of the previous ones
+    fn decode_token<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> jsonwebtoken::errors::Result<jsonwebtoken::TokenData<T>> {
+        let res = jsonwebtoken::decode::<T>(token, &self.get_decoding_secret(), validation);
+        if res.is_ok() {
+            return res;
+        }
+
+        self.previous_app_secrets
+            .iter()
+            .find_map(|s| {
+                jsonwebtoken::decode::<T>(token, &jsonwebtoken::DecodingKey::from_secret(s.as_ref()), validation).ok()
+            })
+            .map_or(res, Ok)
+    }
+
     fn jwt_algorithm(&self) -> jsonwebtoken::Algorithm {
         jsonwebtoken::Algorithm::HS512
     }
 
+    /// Name of the service tokens are issued for, derived from the access URL
+    fn service_name(&self) -> &str {
+        if let Some(service) = &self.token_service {
+            return service;
+        }
+
+        // Only keep the host and the port of the access URL
+        let url = self.access_url.split("://").last().unwrap_or_default();
+        url.split(['/', '?', '#'])
+            .next()
+            .filter(|h| !h.is_empty())
+            .unwrap_or("dockerust")
+    }
+
     fn get_auth_validation_algorithm(&self) -> jsonwebtoken::Validation {
         let mut val = Validation::new(self.jwt_algorithm());
         val.validate_exp = false;
-        val.required_spec_claims = HashSet::new();
+        val.required_spec_claims = HashSet::from(["iss".to_string(), "aud".to_string()]);
+        val.set_issuer(&[&self.access_url]);
+        val.set_audience(&[self.service_name()]);
         val
     }
 
+    /// Audience of refresh tokens, distinct from the one of auth tokens so that
+    /// they can not be used in place of each other
+    fn refresh_audience(&self) -> String {
+        format!("{}/refresh", self.service_name())
+    }
+
+    fn get_refresh_validation_algorithm(&self) -> jsonwebtoken::Validation {
+        let mut val = self.get_auth_validation_algorithm();
+        val.set_audience(&[self.refresh_audience()]);
+        val
+    }
+
+    /// Check if a user can administer the registry. Every user is an administrator when
+    /// authentication is disabled
+    pub fn is_admin(&self, user: &str) -> bool {
+        !self.need_auth() || self.credentials.iter().any(|c| c.admin && c.user_name.eq(user))
+    }
+
+    pub fn has_user(&self, user: &str) -> bool {
+        self.credentials.iter().any(|c| c.user_name.eq(user))
+    }
+
     pub fn check_auth(&self, user: &str, password: &str) -> bool {
         for cred in &self.credentials {
             if cred.user_name.eq(user) && bcrypt::verify(password, &cred.password_hash).unwrap_or(false) {
@@ -90,34 +615,92 @@ impl ServerConfig {
     }
 }
 
+/// Apply a change to the size index of the registry, if it is enabled
+pub fn update_index<F>(conf: &ServerConfig, f: F) -> RegistryResult<()>
+where
+    F: FnOnce(&mut SizeIndex) -> RegistryResult<()>,
+{
+    match conf.index_backend {
+        None => Ok(()),
+        Some(IndexBackend::Json) => SizeIndex::update(&conf.registry_path(), f),
+    }
+}
+
+/// Parse an address of a forwarding header, which may be quoted and include a port
+fn parse_forwarded_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim().trim_matches('"');
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+
+    s.parse::<SocketAddr>()
+        .ok()
+        .map(|a| a.ip())
+        .or_else(|| s.strip_prefix('[')?.split_once(']')?.0.parse().ok())
+}
+
 fn ok_or_internal_error<E>(r: Result<HttpResponse, E>) -> HttpResponse
 where
-    E: Error,
+    E: Error + Into<RegistryError>,
 {
     match r {
         Ok(e) => e,
-        Err(e) => {
-            println!("Error! {}", e);
-            HttpResponse::InternalServerError().body("500 Internal Server Error")
-        }
+        Err(e) => HttpResponse::from_error(e.into()),
+    }
+}
+
+/// Get the URL clients reach the registry at. The headers set by reverse proxies are
+/// taken into account when they are trusted
+fn public_url(req: &HttpRequest, conf: &ServerConfig) -> String {
+    let from_proxy = req.peer_addr().is_some_and(|a| conf.is_trusted_proxy(&a.ip()));
+    if !conf.trust_forwarded_headers || !from_proxy {
+        return conf.access_url.to_string();
+    }
+
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.split(',').next())
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+    };
+
+    let (scheme, rest) = conf.access_url.split_once("://").unwrap_or(("http", &conf.access_url));
+    let (host, path) = rest.split_once('/').map_or((rest, ""), |(h, p)| (h, p));
+
+    let scheme = header("x-forwarded-proto")
+        .filter(|p| ["http", "https"].contains(p))
+        .unwrap_or(scheme);
+    let host = header("x-forwarded-host").unwrap_or(host);
+
+    match path {
+        "" => format!("{}://{}", scheme, host),
+        path => format!("{}://{}/{}", scheme, host, path),
     }
 }
 
-fn request_auth(conf: &ServerConfig, error: Option<&'static str>) -> HttpResponse {
-    let realm = format!("{}/token", conf.access_url);
-    let service = conf.access_url.split("://").last().unwrap_or("dockerust");
+/// Ask the client to authenticate. The service stays the configured one, as issued
+/// tokens are bound to it
+fn request_auth(req: &HttpRequest, conf: &ServerConfig, error: Option<&'static str>) -> HttpResponse {
+    let realm = format!("{}/token", public_url(req, conf));
+    let service = conf.service_name();
 
     let complement = match error {
         None => "".to_string(),
         Some(e) => format!(",error=\"{}\"", e),
     };
+    let scope = requested_access(req)
+        .map(|(name, action)| format!("repository:{}:{}", name, action))
+        .unwrap_or_else(|| "access".to_string());
 
     HttpResponse::Unauthorized()
+        .insert_header(API_VERSION_HEADER)
         .insert_header((
             "WWW-Authenticate",
             format!(
-                "Bearer realm=\"{}\",service=\"{}\",scope=\"access\"{}",
-                realm, service, complement
+                "Bearer realm=\"{}\",service=\"{}\",scope=\"{}\"{}",
+                realm, service, scope, complement
             ),
         ))
         .json(DockerErrorResponse::new_simple(
@@ -127,6 +710,22 @@ fn request_auth(conf: &ServerConfig, error: Option<&'static str>) -> HttpRespons
 }
 
 fn check_auth(req: &HttpRequest, conf: &ServerConfig, user: &mut Option<String>) -> Option<HttpResponse> {
+    if conf.client_ca_path.is_none() {
+        return check_credentials(req, conf, user);
+    }
+
+    // With client certificates, only the clients presenting one are allowed to push
+    if let Some(identity) = req.conn_data::<ClientIdentity>() {
+        *user = Some(identity.0.clone());
+        return None;
+    }
+
+    let res = check_credentials(req, conf, user);
+    *user = None;
+    res
+}
+
+fn check_credentials(req: &HttpRequest, conf: &ServerConfig, user: &mut Option<String>) -> Option<HttpResponse> {
     if !conf.need_auth() {
         *user = Some("anonymous".to_string());
         return None;
@@ -141,25 +740,28 @@ fn check_auth(req: &HttpRequest, conf: &ServerConfig, user: &mut Option<String>)
         .replace("Bearer ", "");
 
     if auth_part.is_empty() {
-        return Some(request_auth(conf, None));
+        return Some(request_auth(req, conf, None));
     }
 
-    let token = jsonwebtoken::decode::<JWTClaims>(
-        &auth_part,
-        &conf.get_decoding_secret(),
-        &conf.get_auth_validation_algorithm(),
-    );
+    let token = conf.decode_token::<JWTClaims>(&auth_part, &conf.get_auth_validation_algorithm());
 
     let token = match token {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Failed to decode JWT token: {}", e);
-            return Some(request_auth(conf, None));
+            log_error!("Failed to decode JWT token: {}", e);
+            return Some(request_auth(req, conf, None));
         }
     };
 
     if token.claims.timeout < time() {
-        return Some(request_auth(conf, Some("invalid_token")));
+        return Some(request_auth(req, conf, Some("invalid_token")));
+    }
+
+    // Tokens only grant access to the repositories of their scope
+    if let Some((name, action)) = requested_access(req) {
+        if !scope_allows(token.claims.scope.as_deref().unwrap_or(""), &name, action) {
+            return Some(insufficient_authorizations(req, conf));
+        }
     }
 
     if let Some(id) = token.claims.user {
@@ -169,44 +771,207 @@ fn check_auth(req: &HttpRequest, conf: &ServerConfig, user: &mut Option<String>)
     None
 }
 
-fn insufficient_authorizations(conf: &ServerConfig) -> HttpResponse {
-    request_auth(conf, Some("insufficient_scope"))
+/// Get the repository a request is about, and the action of the scopes it requires
+fn requested_access(req: &HttpRequest) -> Option<(String, &'static str)> {
+    let parts = req.path().strip_prefix("/v2/")?.split('/').collect::<Vec<_>>();
+    let len = parts.len();
+    if len < 2 {
+        return None;
+    }
+
+    let name_len = if parts[len - 1].is_empty() {
+        // Upload requests `/<name>/blobs/uploads/` and repository deletion `/<name>/`
+        match len >= 4 && parts[len - 3] == "blobs" && parts[len - 2] == "uploads" {
+            true => len - 3,
+            false => len - 1,
+        }
+    } else if parts[len - 1] == "_manifests" {
+        len - 1
+    } else if len >= 4
+        && ((parts[len - 3] == "blobs" && (parts[len - 2] == "uploads" || parts[len - 1] == "_refs"))
+            || (parts[len - 3] == "tags" && parts[len - 1] == "digest"))
+    {
+        len - 3
+    } else if len >= 3 && ["manifests", "blobs", "tags", "referrers"].contains(&parts[len - 2]) {
+        len - 2
+    } else {
+        return None;
+    };
+
+    let action = match *req.method() {
+        Method::GET | Method::HEAD => "pull",
+        Method::DELETE => "delete",
+        _ => "push",
+    };
+    Some((parts[..name_len].join("/"), action))
+}
+
+/// Check if a scope, as a list of `repository:<name>:<actions>` separated by spaces, allows
+/// an action on a repository
+fn scope_allows(scope: &str, name: &str, action: &str) -> bool {
+    scope.split(' ').any(|s| {
+        let Some((resource, actions)) = s.rsplit_once(':') else {
+            return false;
+        };
+        resource == format!("repository:{}", name) && actions.split(',').any(|a| a == action || a == "*")
+    })
 }
 
-async fn get_auth_token(config: web::Data<ServerConfig>, r: HttpRequest) -> HttpResponse {
+fn insufficient_authorizations(req: &HttpRequest, conf: &ServerConfig) -> HttpResponse {
+    request_auth(req, conf, Some("insufficient_scope"))
+}
+
+/// Restrict a requested scope to the actions the user is allowed to perform
+fn grant_scope(scope: &str, authenticated: bool) -> String {
+    if authenticated {
+        return scope.to_string();
+    }
+
+    // Anonymous users can only pull
+    scope
+        .split(' ')
+        .filter_map(|s| {
+            let (resource, actions) = s.rsplit_once(':')?;
+            actions
+                .split(',')
+                .any(|a| a == "pull")
+                .then(|| format!("{}:pull", resource))
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reachability probe of the token endpoint: advertises the supported credentials without
+/// checking them nor issuing a token
+async fn probe_auth_token() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(("www-authenticate", "Basic realm=\"dockerust\""))
+        .finish()
+}
+
+async fn get_auth_token(
+    config: web::Data<ServerConfig>,
+    r: HttpRequest,
+    form: web::Either<web::Form<TokenForm>, web::Bytes>,
+) -> HttpResponse {
+    let query = match TokenRequest::from_query(r.query_string()) {
+        Ok(q) => q,
+        Err(_) => {
+            return HttpResponse::BadRequest().json(InvalidAuthResponse {
+                details: "invalid query",
+            })
+        }
+    };
+
     ok_or_internal_error::<std::io::Error>((move || {
+        let form = match form {
+            web::Either::Left(f) => f.into_inner(),
+            web::Either::Right(_) => TokenForm::default(),
+        };
+
+        if let Some(service) = form.service.as_ref().or(query.service.as_ref()) {
+            if service != config.service_name() {
+                return Ok(HttpResponse::BadRequest().json(InvalidAuthResponse {
+                    details: "unknown service",
+                }));
+            }
+        }
+
         let mut user = None;
 
-        let auth_part: String = r
-            .headers()
-            .get("authorization")
-            .map(|s| s.to_str().unwrap_or(""))
-            .unwrap_or("")
-            .to_string()
-            .replace("Basic ", "");
-
-        if !auth_part.is_empty() {
-            let decoded = b64decoder::STANDARD.decode(auth_part).unwrap_or_default();
-            let decoded = String::from_utf8_lossy(&decoded);
-            let split: Vec<&str> = decoded.splitn(2, ':').collect();
-
-            let username = split.first().unwrap_or(&"");
-            let password = split.get(1).unwrap_or(&"");
-
-            if config.check_auth(username, password) {
-                user = Some(username.to_string());
-            } else {
+        // Exchange of a refresh token
+        if form.grant_type.as_deref() == Some("refresh_token") {
+            let refresh = config.decode_token::<RefreshClaims>(
+                form.refresh_token.as_deref().unwrap_or(""),
+                &config.get_refresh_validation_algorithm(),
+            );
+
+            // The user might have been removed since the refresh token was issued
+            match refresh {
+                Ok(t) if t.claims.timeout >= time() && config.has_user(&t.claims.user) => user = Some(t.claims.user),
+                _ => {
+                    return Ok(HttpResponse::Unauthorized().json(InvalidAuthResponse {
+                        details: "invalid refresh token",
+                    }));
+                }
+            }
+        } else {
+            let auth_part: String = r
+                .headers()
+                .get("authorization")
+                .map(|s| s.to_str().unwrap_or(""))
+                .unwrap_or("")
+                .to_string()
+                .replace("Basic ", "");
+
+            if !auth_part.is_empty() {
+                let decoded = b64decoder::STANDARD.decode(auth_part).unwrap_or_default();
+                let decoded = String::from_utf8_lossy(&decoded);
+                let split: Vec<&str> = decoded.splitn(2, ':').collect();
+
+                let username = split.first().unwrap_or(&"");
+                let password = split.get(1).unwrap_or(&"");
+
+                if config.check_auth(username, password) {
+                    user = Some(username.to_string());
+                } else {
+                    return Ok(HttpResponse::Unauthorized()
+                        .insert_header(("www-authenticate", "Basic realm=\"dockerust\""))
+                        .json(InvalidAuthResponse {
+                            details: "incorrect username or password",
+                        }));
+                }
+            }
+        }
+
+        if let Some(account) = &query.account {
+            if user.as_ref().is_some_and(|u| u != account) {
                 return Ok(HttpResponse::Unauthorized()
                     .insert_header(("www-authenticate", "Basic realm=\"dockerust\""))
                     .json(InvalidAuthResponse {
-                        details: "incorrect username or password",
+                        details: "account does not match credentials",
                     }));
             }
         }
 
+        if user.is_none() && config.need_auth() && config.disable_anonymous_tokens {
+            return Ok(HttpResponse::Unauthorized()
+                .insert_header(("www-authenticate", "Basic realm=\"dockerust\""))
+                .json(InvalidAuthResponse {
+                    details: "authentication required",
+                }));
+        }
+
+        let scope = form
+            .scope
+            .or_else(|| (!query.scope.is_empty()).then(|| query.scope.join(" ")))
+            .map(|s| grant_scope(&s, user.is_some() || !config.need_auth()))
+            .filter(|s| !s.is_empty());
+
+        // Refresh tokens are only issued to authenticated users
+        let refresh_token = match (&user, query.offline_token.unwrap_or(false)) {
+            (Some(user), true) => Some(
+                encode(
+                    &jsonwebtoken::Header::new(config.jwt_algorithm()),
+                    &RefreshClaims {
+                        iss: config.access_url.to_string(),
+                        aud: config.refresh_audience(),
+                        user: user.to_string(),
+                        timeout: time() + REFRESH_TOKENS_DURATION,
+                    },
+                    &config.get_encoding_secret(),
+                )
+                .map_err(|_| std::io::Error::other("failed to encode refresh token"))?,
+            ),
+            _ => None,
+        };
+
         let claim = JWTClaims {
+            iss: config.access_url.to_string(),
+            aud: config.service_name().to_string(),
             user,
-            timeout: time() + AUTH_TOKENS_DURATION,
+            scope,
+            timeout: time() + config.token_ttl(),
         };
 
         let token = encode(
@@ -214,12 +979,13 @@ async fn get_auth_token(config: web::Data<ServerConfig>, r: HttpRequest) -> Http
             &claim,
             &config.get_encoding_secret(),
         )
-        .map_err(|_| std::io::Error::new(ErrorKind::Other, "failed to encode token"))?;
+        .map_err(|_| std::io::Error::other("failed to encode token"))?;
 
         Ok(HttpResponse::Ok().json(AuthResponse {
-            access_token: token.to_string(),
-            token,
-            expires_in: AUTH_TOKENS_DURATION,
+            token: config.legacy_token_field.then(|| token.to_string()),
+            access_token: token,
+            expires_in: config.token_ttl(),
+            refresh_token,
         }))
     })())
 }
@@ -233,197 +999,782 @@ async fn base(config: web::Data<ServerConfig>, r: HttpRequest) -> HttpResponse {
     if let Some(e) = check_auth(&r, &config, &mut user) {
         return e;
     }
-    HttpResponse::Ok().finish()
-}
-
-#[derive(serde::Deserialize)]
-struct CatalogRequest {
-    n: Option<usize>,
-    last: Option<String>,
-}
+    HttpResponse::Ok()
+        .insert_header(API_VERSION_HEADER)
+        .json(serde_json::json!({}))
+}
+
+async fn version() -> HttpResponse {
+    HttpResponse::Ok().json(VersionInfo {
+        name: env!("CARGO_PKG_NAME").to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git: option_env!("DOCKERUST_GIT_HASH").map(str::to_string),
+    })
+}
+
+async fn gc_status(config: web::Data<ServerConfig>, state: web::Data<ServerState>, r: HttpRequest) -> HttpResponse {
+    let mut user = None;
+    if let Some(e) = check_auth(&r, &config, &mut user) {
+        return e;
+    }
+    if !user.as_deref().is_some_and(|u| config.is_admin(u)) {
+        return insufficient_authorizations(&r, &config);
+    }
+
+    HttpResponse::Ok().json(state.gc_status())
+}
+
+/// Get the disk usage of the registry, from the size index when it is enabled
+fn get_usage(config: &ServerConfig) -> RegistryResult<HttpResponse> {
+    let index = match config.index_backend {
+        Some(_) => SizeIndex::load(&config.registry_path())?,
+        None => SizeIndex::rebuild(&config.registry_path())?,
+    };
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "total": index.total_size(),
+        "images": index.images,
+    })))
+}
+
+async fn usage(config: web::Data<ServerConfig>, r: HttpRequest) -> HttpResponse {
+    let mut user = None;
+    if let Some(e) = check_auth(&r, &config, &mut user) {
+        return e;
+    }
+    if user.is_none() {
+        return insufficient_authorizations(&r, &config);
+    }
+
+    ok_or_internal_error(get_usage(&config))
+}
+
+fn get_status(config: &ServerConfig, state: &ServerState) -> RegistryResult<HttpResponse> {
+    let (blobs, total_bytes) = match config.index_backend {
+        Some(_) => {
+            let index = SizeIndex::load(&config.registry_path())?;
+            (index.blobs.len(), index.total_size())
+        }
+        None => {
+            let blobs = get_blob_list(&config.registry_path())?;
+            let total_bytes = blobs
+                .iter()
+                .map(|b| b.data_path(&config.registry_path()).metadata().map(|m| m.len()))
+                .sum::<std::io::Result<u64>>()?;
+            (blobs.len(), total_bytes)
+        }
+    };
+
+    Ok(HttpResponse::Ok().json(RegistryStatus {
+        repositories: get_docker_images_list(&config.registry_path())?.len(),
+        blobs,
+        total_bytes,
+        last_gc_at: state.gc_status().last_run,
+    }))
+}
+
+async fn status(config: web::Data<ServerConfig>, state: web::Data<ServerState>, r: HttpRequest) -> HttpResponse {
+    let mut user = None;
+    if let Some(e) = check_auth(&r, &config, &mut user) {
+        return e;
+    }
+    if !user.as_deref().is_some_and(|u| config.is_admin(u)) {
+        return insufficient_authorizations(&r, &config);
+    }
+
+    ok_or_internal_error(get_status(&config, &state))
+}
+
+#[derive(serde::Deserialize)]
+struct CatalogRequest {
+    n: Option<usize>,
+    last: Option<String>,
+    /// Include the last update time of each repository
+    #[serde(default)]
+    extended: bool,
+}
+
+/// Answer a HEAD request with the status and the headers of the response to the GET request
+fn head_response(res: HttpResponse) -> HttpResponse {
+    let (res, body) = res.into_parts();
+    let len = match body.size() {
+        BodySize::Sized(len) => len,
+        _ => 0,
+    };
+
+    let mut head = HttpResponse::build(res.status());
+    for (name, value) in res.headers() {
+        head.append_header((name.clone(), value.clone()));
+    }
+    head.no_chunking(len).finish()
+}
+
+async fn head_catalog(req: web::Query<CatalogRequest>, conf: web::Data<ServerConfig>) -> HttpResponse {
+    head_response(catalog(req, conf).await)
+}
+
+async fn catalog(req: web::Query<CatalogRequest>, conf: web::Data<ServerConfig>) -> HttpResponse {
+    let images = match get_docker_images_list(&conf.registry_path()) {
+        Ok(images) => images,
+        Err(e) => return HttpResponse::from_error(e),
+    };
+
+    // Images are sorted, so the listing resumes right after the last returned one
+    let remaining = match &req.last {
+        None => &images[..],
+        Some(last) => &images[images.partition_point(|i| i <= last)..],
+    };
+
+    // Without `n`, all the remaining images are returned, up to the configured page size
+    let n = req.n.unwrap_or(remaining.len());
+    let n = min(
+        min(n, conf.catalog_max_page_size.map_or(n, NonZeroUsize::get)),
+        remaining.len(),
+    );
+    let repositories = remaining[..n].to_vec();
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header(("X-Total-Count", images.len()));
+
+    // The next page is only advertised when this one was truncated
+    if n > 0 && n < remaining.len() {
+        let extended = if req.extended { "&extended=true" } else { "" };
+        response.insert_header((
+            "Link",
+            format!(
+                "</v2/_catalog?n={}&last={}{}>; rel=\"next\"",
+                n,
+                repositories[n - 1],
+                extended
+            ),
+        ));
+    }
+
+    if !req.extended {
+        return response.json(DockerCatalog { repositories });
+    }
+
+    let entries = repositories
+        .into_iter()
+        .map(|name| {
+            let last_updated = DockerImage::new(&conf.registry_path(), &name).last_updated()?;
+            Ok(DockerCatalogEntry { name, last_updated })
+        })
+        .collect::<RegistryResult<Vec<_>>>();
+
+    ok_or_internal_error(entries.map(|repositories| response.json(DockerExtendedCatalog { repositories })))
+}
+
+fn get_manifest_revisions(image: &DockerImage) -> RegistryResult<HttpResponse> {
+    if !image.image_path().exists() {
+        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::NAME_UNKNOWN,
+            "repository name not known to registry",
+        )));
+    }
+
+    let mut revisions = vec![];
+    for blob_ref in image.manifests_revision_list()? {
+        let mut tags = image.get_tags_attached_to_manifest_blob(&blob_ref)?;
+        tags.sort();
+        revisions.push(DockerManifestRevision {
+            digest: blob_ref.to_digest(),
+            tags,
+        });
+    }
+    revisions.sort_by(|a, b| a.digest.cmp(&b.digest));
+
+    Ok(HttpResponse::Ok().json(DockerManifestRevisions {
+        name: image.image.to_string(),
+        revisions,
+    }))
+}
+
+fn get_tags_list(image: &DockerImage) -> RegistryResult<HttpResponse> {
+    if !image.image_path().exists() {
+        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::NAME_UNKNOWN,
+            "repository name not known to registry",
+        )));
+    }
+
+    let tags = image.tags_list()?;
+
+    Ok(HttpResponse::Ok().json(DockerTagsList {
+        name: image.image.to_string(),
+        tags,
+    }))
+}
+
+/// Check the existence of a tag, without reading the manifest it points to
+fn head_tag(image: &DockerImage, tag: &str) -> RegistryResult<HttpResponse> {
+    check_tag_name(tag).map_err(|e| RegistryError::TagInvalid(format!("{}: {}", tag, e)))?;
+
+    let link_path = image.manifest_tag_link_path(tag);
+    if !link_path.is_file() {
+        return Err(RegistryError::ManifestUnknown(tag.to_string()));
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            "Docker-Content-Digest",
+            BlobReference::from_file(&link_path)?.to_digest(),
+        ))
+        .finish())
+}
+
+/// Resolve a tag to the digest of its manifest, without serving the manifest
+fn get_tag_digest(image: &DockerImage, tag: &str) -> RegistryResult<HttpResponse> {
+    check_tag_name(tag).map_err(|e| RegistryError::TagInvalid(format!("{}: {}", tag, e)))?;
+
+    let link_path = image.manifest_tag_link_path(tag);
+    if !link_path.exists() {
+        return Err(RegistryError::ManifestUnknown(tag.to_string()));
+    }
+
+    Ok(HttpResponse::Ok().json(DockerTagDigest {
+        digest: BlobReference::from_file(&link_path)?.to_digest(),
+    }))
+}
+
+async fn serve_blob(
+    blob_ref: &BlobReference,
+    image: &DockerImage,
+    content_type: &str,
+    watchdog: Option<&IdleWatchdog>,
+) -> RegistryResult<HttpResponse> {
+    let blob_path = blob_ref.existing_data_path(&image.storage_path)?;
+
+    let blob_len = blob_path.metadata()?.len();
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(content_type)
+        .insert_header(("Docker-Content-Digest", blob_ref.to_digest()))
+        .insert_header(("Etag", blob_ref.to_digest()));
+
+    // Small blobs, like configs and manifests, do not need to be streamed
+    if blob_len < SMALL_BLOB_SIZE {
+        return Ok(response.body(std::fs::read(&blob_path)?));
+    }
+
+    let stream = ReadFileStream::new(&blob_path)?;
+    Ok(match watchdog {
+        Some(watchdog) => response.body(SizedStream::new(blob_len, watchdog.watch(stream))),
+        None => response.body(SizedStream::new(blob_len, stream)),
+    })
+}
+
+async fn get_manifest(
+    image: &DockerImage,
+    image_ref: &str,
+    platform: Option<&str>,
+    watchdog: Option<&IdleWatchdog>,
+) -> RegistryResult<HttpResponse> {
+    let blob_ref = image.resolve_manifest(image_ref)?;
+
+    if !image.manifests_revision_list()?.contains(&blob_ref) {
+        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::MANIFEST_BLOB_UNKNOWN,
+            "manifest blob not attached to manifest",
+        )));
+    }
+
+    // Load manifest to get its type
+    let manifest: DockerManifestOrManifestList =
+        serde_json::from_str(&std::fs::read_to_string(blob_ref.data_path(&image.storage_path))?)?;
+
+    // Manifest lists are resolved to the manifest of the requested platform, if any
+    if let (Some(platform), Some(list)) = (platform, manifest.get_manifests_list()) {
+        let Some(child) = list
+            .manifests
+            .iter()
+            .find(|m| m.platform.as_ref().is_some_and(|p| p.matches(platform)))
+        else {
+            return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::MANIFEST_UNKNOWN,
+                "no manifest matches the requested platform",
+            )));
+        };
+
+        return Box::pin(get_manifest(image, &child.digest, None, watchdog)).await;
+    }
+
+    serve_blob(&blob_ref, image, manifest.media_type(), watchdog).await
+}
+
+/// Schema version of a manifest, read without loading the rest of the manifest
+#[allow(non_snake_case)]
+#[derive(serde::Deserialize)]
+struct ManifestSchemaVersion {
+    schemaVersion: Option<u64>,
+}
+
+/// Check an `If-Match` header against the digest of the manifest a tag currently points to.
+/// Entity tags may be quoted, and `*` matches any existing tag
+fn if_match_satisfied(if_match: &str, current: Option<&str>) -> bool {
+    if_match
+        .split(',')
+        .map(|e| e.trim().trim_matches('"'))
+        .any(|e| current.is_some_and(|c| e == "*" || e == c))
+}
+
+async fn put_manifest(
+    image: &DockerImage,
+    image_ref: &str,
+    content_type: &str,
+    if_match: Option<&str>,
+    mut payload: web::Payload,
+    conf: &ServerConfig,
+    state: &ServerState,
+) -> RegistryResult<HttpResponse> {
+    // Stream the manifest to a temporary file, hashing it on the fly
+    let temp_path = conf.temp_path();
+    std::fs::create_dir_all(&temp_path)?;
+    let temp = mktemp::Temp::new_file_in(&temp_path)?;
+
+    let mut file = std::fs::File::create(&temp)?;
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(item) = payload.next().await {
+        let chunk = item.map_err(|_| std::io::Error::other("Failed to read a chunk of data"))?;
+
+        // Streamed payloads are not limited by the payload config
+        size += chunk.len();
+        if size > conf.max_body_size() {
+            return Ok(HttpResponse::PayloadTooLarge().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::SIZE_INVALID,
+                "manifest exceeds the maximum body size",
+            )));
+        }
+
+        hasher.update(&chunk);
+        file.write_all(&chunk)?;
+    }
+    drop(file);
+
+    let read_manifest = || -> std::io::Result<_> { Ok(std::io::BufReader::new(std::fs::File::open(&temp)?)) };
+
+    // Check manifest type
+    let content_type = content_type.split(';').next().unwrap_or("").trim();
+
+    // Schema 1 manifests are deprecated and not supported by this registry
+    let schema_version = serde_json::from_reader::<_, ManifestSchemaVersion>(read_manifest()?)
+        .ok()
+        .and_then(|v| v.schemaVersion);
+    if schema_version == Some(1) || [DOCKER_MANIFEST_V1, DOCKER_MANIFEST_V1_SIGNED].contains(&content_type) {
+        return Ok(HttpResponse::BadRequest()
+            .insert_header((
+                "Warning",
+                "299 - \"Docker schema 1 manifests are not supported, push schema 2 or OCI manifests instead\"",
+            ))
+            .json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::MANIFEST_INVALID,
+                "schema 1 manifests are not supported",
+            )));
+    }
+
+    if !MANIFEST_MEDIA_TYPES.contains(&content_type) {
+        return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::MANIFEST_INVALID,
+            "unsupported manifest content type",
+        )));
+    }
+
+    let manifest = match serde_json::from_reader::<_, DockerManifestOrManifestList>(read_manifest()?) {
+        Ok(m) if !conf.manifest_within_limits(&m) => {
+            return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::MANIFEST_INVALID,
+                "manifest references too many layers or manifests",
+            )))
+        }
+        // Version 2 is the only one of both image manifests and manifest lists
+        Ok(m) if m.schemaVersion != 2 => {
+            return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::MANIFEST_INVALID,
+                "unsupported manifest schema version",
+            )))
+        }
+        Ok(m) if m.media_type().eq(content_type) => m,
+        Ok(_) => {
+            return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::MANIFEST_INVALID,
+                "manifest media type does not match content type",
+            )))
+        }
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::MANIFEST_INVALID,
+                "manifest is not a valid JSON document",
+            )))
+        }
+    };
+
+    let subject = manifest.subject.as_ref().map(|s| s.digest.clone());
+    let blob_ref = BlobReference::from_sha256sum(format!("{:x}", hasher.finalize()));
+
+    // Manifests pushed by digest must match it
+    if BlobReference::is_valid_reference(image_ref) && !blob_ref.to_digest().eq(image_ref) {
+        return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::DIGEST_INVALID,
+            "manifest digest does not match its content",
+        )));
+    }
+
+    // Serialize concurrent pushes to the same image, and keep the blobs the manifest
+    // references from being deleted meanwhile
+    let _references = state.lock_blob_references();
+    let lock = image.write_lock();
+    let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+    // Existing tags can not be moved in immutable tags mode, pushing them again is harmless
+    let is_tag = !BlobReference::is_valid_reference(image_ref);
+    let tag_link = image.manifest_tag_link_path(image_ref);
+    if is_tag
+        && conf.is_immutable_tag(image_ref)
+        && tag_link.exists()
+        && BlobReference::from_file(&tag_link).ok().as_ref() != Some(&blob_ref)
+    {
+        return Ok(HttpResponse::Conflict().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::MANIFEST_INVALID,
+            "tag already exists and tags are immutable",
+        )));
+    }
+
+    // Tags are only moved if they still point to the manifest the client expects
+    if let (true, Some(if_match)) = (is_tag, if_match) {
+        let link = image.manifest_tag_link_path(image_ref);
+        let current = match link.exists() {
+            true => Some(BlobReference::from_file(&link)?.to_digest()),
+            false => None,
+        };
+
+        if !if_match_satisfied(if_match, current.as_deref()) {
+            return Ok(HttpResponse::PreconditionFailed().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::DENIED,
+                "tag does not point to the expected manifest",
+            )));
+        }
+    }
+
+    // The blobs the manifest references for the first time count in the quota, along with the
+    // ones pushed to the image but not referenced yet
+    let mut new_blobs = BTreeSet::new();
+    manifest_referenced_blobs(&manifest, &conf.registry_path(), &mut new_blobs)?;
+    let mut blobs = state.pushed_blobs(&image.image, conf.gc_grace_period());
+    for digest in new_blobs {
+        let size = BlobReference::from_str(&digest)?
+            .data_path(&conf.registry_path())
+            .metadata()
+            .map_or(0, |m| m.len());
+        blobs.push((digest, size));
+    }
+    blobs.push((blob_ref.to_digest(), std::fs::metadata(&temp)?.len()));
+
+    if conf.exceeds_quota(image, blobs)? {
+        return Ok(quota_exceeded());
+    }
+
+    // A stored manifest is only reused if it was not corrupted since it was pushed
+    let blob_path = blob_ref.data_path(&conf.registry_path());
+    if blob_path.exists() && !is_blob_intact(&conf.registry_path(), &blob_ref)? {
+        log_error!("Stored manifest {} is corrupted, rewriting it", blob_ref.to_digest());
+        std::fs::remove_file(&blob_path)?;
+    }
+
+    // Move manifest to its destination, unless the same manifest was already pushed
+    commit_blob(&conf.registry_path(), &temp, &blob_ref, conf.durable_writes)?;
+    temp.release();
+
+    // Write references to manifest, adding a tag only if it is not a valid digest
+    link_manifest(image, is_tag.then_some(image_ref), &blob_ref, conf.durable_writes)?;
+
+    update_index(conf, |i| {
+        i.add_blob(&blob_ref, &conf.registry_path())?;
+        i.update_image(image)
+    })?;
+
+    let location = format!(
+        "{}/v2/{}/manifests/{}",
+        conf.access_url,
+        image.image,
+        blob_ref.to_digest()
+    );
+
+    let mut response = HttpResponse::Created();
+    response
+        .insert_header(("Docker-Content-Digest", blob_ref.to_digest()))
+        .insert_header(("location", location));
+
+    // Tell the client its subject was processed, so that it does not maintain a referrers tag
+    if let Some(subject) = subject {
+        response.insert_header(("OCI-Subject", subject));
+    }
+
+    Ok(response.finish())
+}
+
+async fn delete_manifest(
+    image: &DockerImage,
+    digest: &str,
+    conf: &ServerConfig,
+    state: &ServerState,
+) -> RegistryResult<HttpResponse> {
+    // Tags are removed along with the manifest they point to
+    if !BlobReference::is_valid_reference(digest) && image.manifest_tag_link_path(digest).exists() {
+        return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::UNSUPPORTED,
+            "manifests can only be deleted by digest",
+        )));
+    }
+
+    let blob = BlobReference::from_str(digest)?;
+
+    if !image.manifests_revision_list()?.contains(&blob) {
+        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::MANIFEST_BLOB_UNKNOWN,
+            "manifest blob not attached to manifest",
+        )));
+    }
 
-async fn catalog(req: web::Query<CatalogRequest>, conf: web::Data<ServerConfig>) -> HttpResponse {
-    let images = match get_docker_images_list(&conf.storage_path) {
-        Ok(images) => images,
-        Err(e) => {
-            eprintln!("Failed to get the list of images! {:?}", e);
-            return HttpResponse::InternalServerError().json("500 Internal Error");
-        }
-    };
+    let lock = image.write_lock();
+    let guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
 
-    if images.is_empty() {
-        return HttpResponse::Ok().json(DockerCatalog { repositories: vec![] });
+    // Remove tags
+    for tag in image.get_tags_attached_to_manifest_blob(&blob)? {
+        std::fs::remove_dir_all(image.tags_path().join(tag))?;
     }
 
-    let start = match &req.last {
-        None => 0,
-        Some(s) => images.iter().position(|f| f.eq(s)).map(|f| f + 1).unwrap_or(0),
-    };
-    let end = start + req.n.unwrap_or(images.len() + 1);
+    // Remove reference
+    std::fs::remove_file(image.manifest_revision_path(&blob))?;
 
-    HttpResponse::Ok().json(DockerCatalog {
-        repositories: images[min(start, images.len() - 1)..min(images.len(), end)].to_vec(),
-    })
+    // Run garbage collector. The image lock is released first, as pushes take the blob
+    // references lock before it
+    drop(guard);
+    state.run_gc(&conf.registry_path(), conf.gc_grace_period())?;
+    update_index(conf, |i| {
+        i.prune(&conf.registry_path());
+        i.update_image(image)
+    })?;
+
+    Ok(HttpResponse::Accepted().finish())
 }
 
-fn get_tags_list(image: &DockerImage) -> std::io::Result<HttpResponse> {
-    if !image.image_path().exists() {
-        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-            DockerErrorMessageType::NAME_UNKNOWN,
-            "repository name not known to registry",
-        )));
-    }
+fn get_blob_referrers(image: &DockerImage, digest: &str) -> RegistryResult<HttpResponse> {
+    let blob_ref = BlobReference::from_str(digest)?;
+    blob_ref.existing_data_path(&image.storage_path)?;
 
-    let tags = image.tags_list()?;
+    let manifests = image.manifests_referencing_blob(&blob_ref)?;
 
-    Ok(HttpResponse::Ok().json(DockerTagsList {
+    let mut tags = vec![];
+    for manifest in &manifests {
+        tags.append(&mut image.get_tags_attached_to_manifest_blob(manifest)?);
+    }
+    tags.sort();
+
+    Ok(HttpResponse::Ok().json(DockerBlobReferrers {
         name: image.image.to_string(),
+        digest: blob_ref.to_digest(),
+        manifests: manifests.iter().map(BlobReference::to_digest).collect(),
         tags,
     }))
 }
 
-async fn serve_blob(
-    blob_ref: &BlobReference,
-    image: &DockerImage,
-    content_type: &str,
-) -> std::io::Result<HttpResponse> {
-    let blob_path = blob_ref.data_path(&image.storage_path);
+/// List the manifests of an image whose subject is a given manifest, as an OCI image index
+fn get_referrers(image: &DockerImage, digest: &str, artifact_type: Option<&str>) -> RegistryResult<HttpResponse> {
+    let subject = BlobReference::from_str(digest)?;
 
-    if !blob_path.exists() {
-        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-            DockerErrorMessageType::BLOB_UNKNOWN,
-            "blob not found",
-        )));
-    }
+    let mut manifests = vec![];
+    for manifest_ref in image.manifests_revision_list()? {
+        let manifest_path = manifest_ref.data_path(&image.storage_path);
+        let Ok(content) = std::fs::read(&manifest_path) else {
+            continue;
+        };
+        let manifest: DockerManifestOrManifestList = serde_json::from_slice(&content)?;
+
+        if manifest
+            .subject
+            .as_ref()
+            .is_none_or(|s| s.digest != subject.to_digest())
+        {
+            continue;
+        }
 
-    let blob_len = blob_path.metadata()?.len();
+        // Image manifests without artifact type are typed by their config
+        let manifest_artifact_type = manifest
+            .artifactType
+            .clone()
+            .or_else(|| manifest.config.as_ref().map(|c| c.mediaType.clone()));
+        if artifact_type.is_some_and(|t| manifest_artifact_type.as_deref() != Some(t)) {
+            continue;
+        }
+
+        manifests.push(DockerReferrer {
+            mediaType: manifest.media_type().to_string(),
+            digest: manifest_ref.to_digest(),
+            size: content.len() as u64,
+            artifactType: manifest_artifact_type,
+            annotations: manifest.annotations,
+        });
+    }
+    manifests.sort_by(|a, b| a.digest.cmp(&b.digest));
 
     let mut response = HttpResponse::Ok();
     response
-        .content_type(content_type)
-        .insert_header(("Docker-Content-Digest", blob_ref.to_digest()))
-        .insert_header(("Etag", blob_ref.to_digest()));
+        .content_type(OCI_IMAGE_INDEX_V1)
+        .insert_header(("OCI-Subject", subject.to_digest()));
+    if artifact_type.is_some() {
+        response.insert_header(("OCI-Filters-Applied", "artifactType"));
+    }
 
-    Ok(response.body(SizedStream::new(blob_len, ReadFileStream::new(&blob_path)?)))
+    Ok(response.json(DockerReferrersIndex {
+        schemaVersion: 2,
+        mediaType: OCI_IMAGE_INDEX_V1.to_string(),
+        manifests,
+    }))
 }
 
-async fn get_manifest(image: &DockerImage, image_ref: &str) -> std::io::Result<HttpResponse> {
+async fn get_blob(image: &DockerImage, digest: &str, watchdog: Option<&IdleWatchdog>) -> RegistryResult<HttpResponse> {
     // Requested hash is included in the request
-    let blob_ref = if image_ref.starts_with("sha256") {
-        BlobReference::from_str(image_ref)?
-    }
-    // We must find ourselves the blob to load
-    else {
-        let manifest_path = image.manifest_tag_link_path(image_ref);
-
-        if !manifest_path.exists() {
-            return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-                DockerErrorMessageType::MANIFEST_UNKNOWN,
-                "manifest unknown",
-            )));
-        }
+    let blob_ref = BlobReference::from_str(digest)?;
 
-        BlobReference::from_file(&manifest_path)?
-    };
+    // Strict clients expect config blobs to be served with their declared media type
+    let media_type = image
+        .blob_media_type(&blob_ref)?
+        .unwrap_or_else(|| "application/octet-stream".to_string());
 
-    if !image.manifests_revision_list()?.contains(&blob_ref) {
-        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-            DockerErrorMessageType::MANIFEST_BLOB_UNKNOWN,
-            "manifest blob not attached to manifest",
-        )));
-    }
+    serve_blob(&blob_ref, image, &media_type, watchdog).await
+}
 
-    // Load manifest to get its type
-    let manifest: DockerManifestOrManifestList =
-        serde_json::from_str(&std::fs::read_to_string(blob_ref.data_path(&image.storage_path))?)?;
+/// Check the existence of a blob, without reading it
+fn head_blob(image: &DockerImage, digest: &str) -> RegistryResult<HttpResponse> {
+    let blob_ref = BlobReference::from_str(digest)?;
+    let blob_len = blob_ref.existing_data_path(&image.storage_path)?.metadata()?.len();
 
-    serve_blob(&blob_ref, image, &manifest.mediaType).await
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(("Docker-Content-Digest", blob_ref.to_digest()))
+        .insert_header(("Etag", blob_ref.to_digest()))
+        .no_chunking(blob_len)
+        .finish())
 }
 
-async fn put_manifest(
+async fn delete_blob(
     image: &DockerImage,
-    image_ref: &str,
-    mut payload: web::Payload,
+    digest: &str,
     conf: &ServerConfig,
-) -> std::io::Result<HttpResponse> {
-    // Get manifest data
-    let mut bytes = web::BytesMut::new();
-    while let Some(item) = payload.next().await {
-        bytes.extend_from_slice(
-            &item.map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to read a chunk of data"))?,
-        );
+    state: &ServerState,
+) -> RegistryResult<HttpResponse> {
+    if !conf.enable_delete {
+        return Ok(HttpResponse::MethodNotAllowed().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::UNSUPPORTED,
+            "blobs are automatically garbage collected",
+        )));
     }
 
-    let manifest = String::from_utf8(bytes.as_ref().to_vec())
-        .map_err(|_| std::io::Error::new(ErrorKind::Other, "Failed to turn the manifest into a string"))?;
+    let blob_ref = BlobReference::from_str(digest)?;
+    let blob_path = blob_ref.existing_data_path(&image.storage_path)?;
 
-    let blob_ref = BlobReference::from_sha256sum(sha256sum_str(&manifest)?);
-
-    // Write manifest
-    let blob_path = blob_ref.data_path(&conf.storage_path);
-    create_empty_file(&blob_path)?;
-    std::fs::write(blob_path, manifest)?;
-
-    // Write references to manifest
-    let mut list = vec![image.manifest_revision_path(&blob_ref)];
-
-    // Add a tag only if it is not a valid digest
-    if !BlobReference::is_valid_reference(image_ref) {
-        list.push(image.manifest_tag_link_path(image_ref));
-    }
+    // Any image can reference the blob, not only this one
+    let _guard = state.lock_blob_deletion();
 
-    for manifest_path in list {
-        create_empty_file(&manifest_path)?;
-        std::fs::write(manifest_path, blob_ref.to_digest())?;
+    if !is_blob_useless(&blob_ref, &image.storage_path)? {
+        return Ok(HttpResponse::Conflict().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::DENIED,
+            "blob is referenced by a manifest",
+        )));
     }
 
-    let location = format!(
-        "{}/v2/{}/manifests/{}",
-        conf.access_url,
-        image.image,
-        blob_ref.to_digest()
-    );
+    std::fs::remove_dir_all(blob_path.parent().unwrap_or(&blob_path))?;
+    update_index(conf, |i| {
+        i.remove_blob(&blob_ref);
+        Ok(())
+    })?;
 
-    Ok(HttpResponse::Created()
-        .insert_header(("Docker-Content-Digest", blob_ref.to_digest()))
-        .insert_header(("location", location))
-        .finish())
+    Ok(HttpResponse::Accepted().finish())
 }
 
-async fn delete_manifest(image: &DockerImage, digest: &str, conf: &ServerConfig) -> std::io::Result<HttpResponse> {
-    let blob = BlobReference::from_str(digest)?;
+/// Delete the tags and the manifests of a repository, along with the blobs only it referenced
+fn delete_repository(image: &DockerImage, conf: &ServerConfig, state: &ServerState) -> RegistryResult<HttpResponse> {
+    if !conf.enable_delete {
+        return Ok(HttpResponse::MethodNotAllowed().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::UNSUPPORTED,
+            "repository deletion is disabled",
+        )));
+    }
 
-    if !image.manifests_revision_list()?.contains(&blob) {
+    let manifests_path = image.image_path().join("_manifests");
+    if !manifests_path.exists() {
         return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-            DockerErrorMessageType::MANIFEST_BLOB_UNKNOWN,
-            "manifest blob not attached to manifest",
+            DockerErrorMessageType::NAME_UNKNOWN,
+            "repository name not known to registry",
         )));
     }
 
-    // Remove tags
-    for tag in image.get_tags_attached_to_manifest_blob(&blob)? {
-        std::fs::remove_dir_all(image.tags_path().join(tag))?;
+    // Never remove anything outside of the repositories, whatever the name resolves to
+    let repositories = conf.registry_path().join("repositories").canonicalize()?;
+    if !image.image_path().canonicalize()?.starts_with(&repositories) {
+        return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::NAME_INVALID,
+            "invalid repository name",
+        )));
     }
 
-    // Remove reference
-    std::fs::remove_file(image.manifest_revision_path(&blob))?;
+    let lock = image.write_lock();
+    let guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+    // Repositories nested under this one are kept
+    std::fs::remove_dir_all(&manifests_path)?;
+
+    // Uploads in progress end with the repository. Only the files are removed, as the
+    // directory can hold the uploads of the nested repositories
+    let uploads_path = image.uploads_path();
+    state.end_uploads_in(&uploads_path);
+    if uploads_path.exists() {
+        for entry in std::fs::read_dir(&uploads_path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        let _ = std::fs::remove_dir(&uploads_path);
+    }
+    let mut dir = image.image_path();
+    while dir != conf.registry_path().join("repositories") && std::fs::remove_dir(&dir).is_ok() {
+        dir.pop();
+    }
 
-    // Run garbage collector
-    clean_storage(&conf.storage_path)?;
+    // Like for manifests, the image lock must not be held while waiting for the garbage collector
+    drop(guard);
+    state.run_gc(&conf.registry_path(), conf.gc_grace_period())?;
+    update_index(conf, |i| {
+        i.prune(&conf.registry_path());
+        i.update_image(image)
+    })?;
 
     Ok(HttpResponse::Accepted().finish())
 }
 
-async fn get_blob(image: &DockerImage, digest: &str) -> std::io::Result<HttpResponse> {
-    // Requested hash is included in the request
-    serve_blob(&BlobReference::from_str(digest)?, image, "application/octet-stream").await
+/// Get the `Range` header of an upload which received `len` bytes. The end of the range is
+/// the offset of the last received byte, so clients resume at the end plus one. Empty
+/// uploads are reported as `0-0`, like the reference registry does
+fn upload_range(len: u64) -> String {
+    format!("0-{}", len.saturating_sub(1))
+}
+
+fn upload_unknown() -> HttpResponse {
+    HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
+        DockerErrorMessageType::BLOB_UPLOAD_UNKNOWN,
+        "blob upload unknown to registry",
+    ))
 }
 
-async fn delete_blob(_image: &DockerImage, _digest: &str) -> std::io::Result<HttpResponse> {
-    Ok(HttpResponse::MethodNotAllowed().json(DockerErrorResponse::new_simple(
-        DockerErrorMessageType::UNSUPPORTED,
-        "blobs are automatically garbage collected",
-    )))
+/// Identify the upload a response is about, whatever its outcome
+fn with_upload_uuid(mut res: HttpResponse, uuid: &str) -> HttpResponse {
+    if let (Ok(name), Ok(value)) = (HeaderName::try_from(UPLOAD_UUID_HEADER), HeaderValue::from_str(uuid)) {
+        res.headers_mut().insert(name, value);
+    }
+    res
 }
 
 fn blob_upload_response(
@@ -431,70 +1782,89 @@ fn blob_upload_response(
     image: &DockerImage,
     uuid: &str,
     config: &ServerConfig,
-) -> std::io::Result<HttpResponse> {
+) -> RegistryResult<HttpResponse> {
     let location = format!("{}/v2/{}/blobs/uploads/{}", config.access_url, &image.image, uuid);
 
-    let offset = match std::fs::metadata(image.upload_storage_path(uuid))?.len() {
-        0 => 0,
-        s => s - 1,
+    // The upload may be cancelled concurrently
+    let received = match std::fs::metadata(image.upload_storage_path(uuid)) {
+        Ok(m) => m.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(upload_unknown()),
+        Err(e) => return Err(e.into()),
     };
 
     Ok(res
-        .insert_header(("Range", format!("0-{}", offset)))
+        .insert_header(("Range", upload_range(received)))
         .insert_header(("Location", location))
-        .insert_header(("Docker-Upload-Uuid", uuid))
+        .insert_header((UPLOAD_UUID_HEADER, uuid))
         .finish())
 }
 
-async fn start_blob_upload(image: &DockerImage, config: &ServerConfig) -> std::io::Result<HttpResponse> {
+async fn start_blob_upload(
+    image: &DockerImage,
+    user: &str,
+    config: &ServerConfig,
+    state: &ServerState,
+) -> RegistryResult<HttpResponse> {
     let uuid = Uuid::new_v4().to_string();
     let path = image.upload_storage_path(&uuid);
 
-    create_empty_file(&path)?;
+    // Abandoned uploads would otherwise hold their slot until the next restart
+    state.expire_uploads(config.upload_idle_timeout());
+
+    if !state.try_start_upload(
+        &uuid,
+        path.clone(),
+        user,
+        config.max_concurrent_uploads,
+        config.max_concurrent_uploads_per_user,
+    ) {
+        return Ok(HttpResponse::TooManyRequests()
+            .insert_header(("Retry-After", UPLOADS_RETRY_AFTER))
+            .json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::DENIED,
+                "too many concurrent uploads",
+            )));
+    }
+
+    if let Err(e) = create_empty_file(&path) {
+        state.end_upload(&uuid);
+        return Err(e.into());
+    }
 
     blob_upload_response(HttpResponse::Accepted(), image, &uuid, config)
 }
 
-fn blob_upload_status(image: &DockerImage, uuid: &str, config: &ServerConfig) -> std::io::Result<HttpResponse> {
-    if !image.upload_storage_path(uuid).exists() {
-        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-            DockerErrorMessageType::BLOB_UNKNOWN,
-            "blob unknown",
-        )));
-    }
-
+fn blob_upload_status(image: &DockerImage, uuid: &str, config: &ServerConfig) -> RegistryResult<HttpResponse> {
     blob_upload_response(HttpResponse::NoContent(), image, uuid, config)
 }
 
+/// Open an upload to append data to it, if it still exists
+///
+/// The upload may be cancelled concurrently, so its absence is detected when opening it
+fn open_upload(image: &DockerImage, uuid: &str) -> std::io::Result<Option<std::fs::File>> {
+    match OpenOptions::new().append(true).open(image.upload_storage_path(uuid)) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
 async fn process_blob_upload(
     image: &DockerImage,
     uuid: &str,
+    config: &ServerConfig,
     mut payload: web::Payload,
-) -> std::io::Result<Option<HttpResponse>> {
-    let payload_path = image.upload_storage_path(uuid);
-
-    if !payload_path.exists() {
-        return Ok(Some(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-            DockerErrorMessageType::BLOB_UNKNOWN,
-            "blob unknown",
-        ))));
-    }
-
+) -> RegistryResult<Option<HttpResponse>> {
     // Open file
-    let mut file = OpenOptions::new().append(true).open(image.upload_storage_path(uuid))?;
+    let Some(file) = open_upload(image, uuid)? else {
+        return Ok(Some(upload_unknown()));
+    };
+    let mut file = BufWriter::with_capacity(config.upload_buffer_size(), file);
 
     while let Some(chunk) = payload.next().await {
-        match chunk {
-            Ok(c) => {
-                file.write_all(&c)?;
-            }
-            Err(e) => {
-                eprintln!("Failed to read from blob upload request! {:?}", e);
-                return Ok(Some(
-                    HttpResponse::InternalServerError().json("500 Internal Server Error"),
-                ));
-            }
-        }
+        let chunk =
+            chunk.map_err(|e| std::io::Error::other(format!("Failed to read from blob upload request: {}", e)))?;
+        file.write_all(&chunk)?;
     }
 
     file.flush()?;
@@ -508,8 +1878,8 @@ async fn blob_upload_patch(
     uuid: &str,
     config: &ServerConfig,
     payload: web::Payload,
-) -> std::io::Result<HttpResponse> {
-    if let Some(res) = process_blob_upload(image, uuid, payload).await? {
+) -> RegistryResult<HttpResponse> {
+    if let Some(res) = process_blob_upload(image, uuid, config, payload).await? {
         return Ok(res);
     }
 
@@ -520,62 +1890,146 @@ async fn blob_upload_finish(
     image: &DockerImage,
     uuid: &str,
     config: &ServerConfig,
+    state: &ServerState,
     payload: web::Payload,
-    digest: &str,
-) -> std::io::Result<HttpResponse> {
-    // Process last chunk
-    if let Some(res) = process_blob_upload(image, uuid, payload).await? {
+    digest: Option<&str>,
+) -> RegistryResult<HttpResponse> {
+    let res = commit_blob_upload(image, uuid, config, state, payload, digest).await;
+
+    // The slot is released whatever the outcome. The data of the failed uploads is kept
+    // unless it is wrong, so that the client can try to finalize them again
+    state.end_upload(uuid);
+
+    res
+}
+
+/// Check the data of an upload against its digest, and move it to the storage
+async fn commit_blob_upload(
+    image: &DockerImage,
+    uuid: &str,
+    config: &ServerConfig,
+    state: &ServerState,
+    payload: web::Payload,
+    digest: Option<&str>,
+) -> RegistryResult<HttpResponse> {
+    let Some(digest) = digest.filter(|d| !d.is_empty()) else {
+        return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+            DockerErrorMessageType::DIGEST_INVALID,
+            "digest query parameter is required to finalize an upload",
+        )));
+    };
+    let blob_ref = BlobReference::from_str(digest)?;
+
+    let hasher = match blob_ref.alg() {
+        "sha256" => sha256sum,
+        "sha512" => sha512sum,
+        _ => {
+            return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
+                DockerErrorMessageType::DIGEST_INVALID,
+                "unsupported digest algorithm, expected sha256 or sha512",
+            )))
+        }
+    };
+
+    // Process last chunk, which is empty when all the data was sent with PATCH requests
+    if let Some(res) = process_blob_upload(image, uuid, config, payload).await? {
         return Ok(res);
     }
 
-    // Process chunk digest
-    let computed_digest = format!("sha256:{}", sha256sum(&image.upload_storage_path(uuid))?);
+    // Hash the whole uploaded file rather than keeping a running hash in memory, so that
+    // uploads resumed after a restart of the server are finalized with the right digest
+    let computed_digest = format!("{}:{}", blob_ref.alg(), hasher(&image.upload_storage_path(uuid))?);
     if !computed_digest.eq(digest) {
+        if let Err(e) = std::fs::remove_file(image.upload_storage_path(uuid)) {
+            log_error!("Failed to remove failed upload {}: {}", uuid, e);
+        }
         return Ok(HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(
             DockerErrorMessageType::DIGEST_INVALID,
             "invalid digest",
         )));
     }
 
-    // Move blob to its destination
-    let dest = BlobReference::from_str(digest)?.data_path(&config.storage_path);
-    create_empty_file(&dest)?;
-    std::fs::rename(image.upload_storage_path(uuid), &dest)?;
+    // Blobs pushed but not referenced yet take space as well
+    let upload_len = std::fs::metadata(image.upload_storage_path(uuid))?.len();
+    let mut blobs = state.pushed_blobs(&image.image, config.gc_grace_period());
+    blobs.push((blob_ref.to_digest(), upload_len));
+    if config.exceeds_quota(image, blobs)? {
+        return Ok(quota_exceeded());
+    }
 
-    let end_of_blob_range = std::fs::metadata(&dest)?.len() - 1;
+    // Move blob to its destination
+    let dest = blob_ref.data_path(&config.registry_path());
+    commit_blob(
+        &config.registry_path(),
+        &image.upload_storage_path(uuid),
+        &blob_ref,
+        config.durable_writes,
+    )?;
+    state.add_pushed_blob(&image.image, blob_ref.to_digest(), upload_len);
+    update_index(config, |i| i.add_blob(&blob_ref, &config.registry_path()))?;
 
     let location = format!("{}/v2/{}/blobs/{}", config.access_url, &image.image, digest);
 
     Ok(HttpResponse::Created()
-        .insert_header(("Content-Range", format!("0-{}", end_of_blob_range)))
+        .insert_header(("Content-Range", upload_range(std::fs::metadata(&dest)?.len())))
         .insert_header(("Docker-Content-Digest", digest))
         .insert_header(("Location", location))
         .finish())
 }
 
-fn cancel_blob_upload(image: &DockerImage, uuid: &str) -> std::io::Result<HttpResponse> {
-    if !image.upload_storage_path(uuid).exists() {
-        return Ok(HttpResponse::NotFound().json(DockerErrorResponse::new_simple(
-            DockerErrorMessageType::BLOB_UNKNOWN,
-            "blob unknown",
-        )));
+fn cancel_blob_upload(image: &DockerImage, uuid: &str, state: &ServerState) -> RegistryResult<HttpResponse> {
+    let res = std::fs::remove_file(image.upload_storage_path(uuid));
+    state.end_upload(uuid);
+    match res {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(upload_unknown()),
+        res => res?,
     }
 
-    std::fs::remove_file(image.upload_storage_path(uuid))?;
-
     Ok(HttpResponse::NoContent()
         .insert_header(("content-length", "0"))
         .finish())
 }
 
+fn quota_exceeded() -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(DockerErrorResponse::new_simple(
+        DockerErrorMessageType::DENIED,
+        "repository quota exceeded",
+    ))
+}
+
+fn push_denied() -> HttpResponse {
+    HttpResponse::Forbidden().json(DockerErrorResponse::new_simple(
+        DockerErrorMessageType::DENIED,
+        "pushes to this repository are not allowed",
+    ))
+}
+
+/// Get the image designated by the leading parts of a request path, checking its name
+fn requested_image(config: &ServerConfig, parts: &[&str]) -> Result<DockerImage, HttpResponse> {
+    let name = parts.join("/");
+
+    if let Err(e) = check_image_name(&name) {
+        return Err(
+            HttpResponse::BadRequest().json(DockerErrorResponse::new_simple(DockerErrorMessageType::NAME_INVALID, e))
+        );
+    }
+
+    Ok(DockerImage::new(&config.registry_path(), &name).with_upload_path(config.upload_path.as_deref()))
+}
+
 #[derive(serde::Deserialize)]
 struct RequestQuery {
     digest: Option<String>,
+    #[serde(rename = "artifactType")]
+    artifact_type: Option<String>,
+    /// Platform to resolve manifest lists to, as `os/architecture[/variant]`
+    platform: Option<String>,
 }
 
 async fn requests_dispatcher(
     r: HttpRequest,
     config: web::Data<ServerConfig>,
+    state: web::Data<ServerState>,
     payload: web::Payload,
     query: web::Query<RequestQuery>,
 ) -> HttpResponse {
@@ -585,122 +2039,349 @@ async fn requests_dispatcher(
     }
 
     let parts = r.uri().path().split('/').skip(2).collect::<Vec<_>>();
+
+    // Manifest revisions `/v2/<name>/_manifests`
+    if parts.len() >= 2 && parts[parts.len() - 1] == "_manifests" && *r.method() == Method::GET {
+        if user.is_none() {
+            return insufficient_authorizations(&r, &config);
+        }
+
+        let image = match requested_image(&config, &parts[..parts.len() - 1]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
+
+        return ok_or_internal_error(get_manifest_revisions(&image));
+    }
+
+    // Repository deletion `/v2/<name>/`
+    if parts.len() >= 2 && parts[parts.len() - 1].is_empty() && *r.method() == Method::DELETE {
+        if !user.as_deref().is_some_and(|u| config.is_admin(u)) {
+            return insufficient_authorizations(&r, &config);
+        }
+
+        let image = match requested_image(&config, &parts[..parts.len() - 1]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
+
+        return ok_or_internal_error(delete_repository(&image, &config, &state));
+    }
+
     if parts.len() < 3 {
         return not_found().await;
     }
 
     // Get tags list `/v2/<name>/tags/list`
     if r.uri().path().ends_with("/tags/list") {
-        let image = DockerImage::new(&config.storage_path, &parts[..parts.len() - 2].join("/"));
+        let image = match requested_image(&config, &parts[..parts.len() - 2]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
+
+        let res = ok_or_internal_error(get_tags_list(&image));
+        if *r.method() == Method::HEAD {
+            return head_response(res);
+        }
+        return res;
+    }
+    // Tag resolution `/v2/<name>/tags/<tag>/digest`
+    else if parts[parts.len() - 3] == "tags" && parts[parts.len() - 1] == "digest" && *r.method() == Method::GET {
+        let image = match requested_image(&config, &parts[..parts.len() - 3]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
+
+        return ok_or_internal_error(get_tag_digest(&image, parts[parts.len() - 2]));
+    }
+    // Tag existence `/v2/<name>/tags/<tag>`
+    else if parts[parts.len() - 2] == "tags" && *r.method() == Method::HEAD {
+        let image = match requested_image(&config, &parts[..parts.len() - 2]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
 
-        return ok_or_internal_error(get_tags_list(&image));
+        return ok_or_internal_error(head_tag(&image, parts[parts.len() - 1]));
     }
     // Manifest manipulation `/v2/<name>/manifests/<reference>`
     else if parts[parts.len() - 2].eq("manifests") {
-        let image = DockerImage::new(&config.storage_path, &parts[..parts.len() - 2].join("/"));
+        let image = match requested_image(&config, &parts[..parts.len() - 2]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
         let image_ref = parts.last().unwrap();
 
         // Get manifest
         match *r.method() {
-            Method::GET => return ok_or_internal_error(get_manifest(&image, image_ref).await),
-            Method::HEAD => return ok_or_internal_error(get_manifest(&image, image_ref).await),
+            Method::GET | Method::HEAD => {
+                return ok_or_internal_error(
+                    get_manifest(&image, image_ref, query.platform.as_deref(), r.conn_data()).await,
+                )
+            }
             Method::PUT => {
                 if user.is_none() {
-                    return insufficient_authorizations(&config);
+                    return insufficient_authorizations(&r, &config);
+                }
+
+                if !config.is_pushable(&image.image) {
+                    return push_denied();
                 }
 
-                return ok_or_internal_error(put_manifest(&image, image_ref, payload, &config).await);
+                let content_type = r
+                    .headers()
+                    .get("content-type")
+                    .map(|s| s.to_str().unwrap_or(""))
+                    .unwrap_or("");
+                let if_match = r.headers().get("if-match").map(|s| s.to_str().unwrap_or(""));
+
+                return ok_or_internal_error(
+                    put_manifest(&image, image_ref, content_type, if_match, payload, &config, &state).await,
+                );
             }
             Method::DELETE => {
                 if user.is_none() {
-                    return insufficient_authorizations(&config);
+                    return insufficient_authorizations(&r, &config);
                 }
 
-                return ok_or_internal_error(delete_manifest(&image, image_ref, &config).await);
+                return ok_or_internal_error(delete_manifest(&image, image_ref, &config, &state).await);
             }
             _ => {}
         }
     }
     // Blobs manipulation `/v2/<name>/blobs/<digest>`
     else if parts[parts.len() - 2].eq("blobs") {
-        let image = DockerImage::new(&config.storage_path, &parts[..parts.len() - 2].join("/"));
+        let image = match requested_image(&config, &parts[..parts.len() - 2]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
         let digest = parts.last().unwrap();
 
         match *r.method() {
-            Method::GET => return ok_or_internal_error(get_blob(&image, digest).await),
-            Method::HEAD => return ok_or_internal_error(get_blob(&image, digest).await),
+            Method::GET => return ok_or_internal_error(get_blob(&image, digest, r.conn_data()).await),
+            Method::HEAD => return ok_or_internal_error(head_blob(&image, digest)),
             Method::DELETE => {
                 if user.is_none() {
-                    return insufficient_authorizations(&config);
+                    return insufficient_authorizations(&r, &config);
                 }
 
-                return ok_or_internal_error(delete_blob(&image, digest).await);
+                return ok_or_internal_error(delete_blob(&image, digest, &config, &state).await);
             }
             _ => {}
         }
     }
+    // Blob referrers `/v2/<name>/blobs/<digest>/_refs`
+    else if parts[parts.len() - 3] == "blobs" && parts[parts.len() - 1] == "_refs" && *r.method() == Method::GET {
+        if user.is_none() {
+            return insufficient_authorizations(&r, &config);
+        }
+
+        let image = match requested_image(&config, &parts[..parts.len() - 3]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
+
+        return ok_or_internal_error(get_blob_referrers(&image, parts[parts.len() - 2]));
+    }
+    // Manifest referrers `/v2/<name>/referrers/<digest>`
+    else if parts[parts.len() - 2] == "referrers" && *r.method() == Method::GET {
+        let image = match requested_image(&config, &parts[..parts.len() - 2]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
+
+        let artifact_type = query.artifact_type.as_deref();
+        return ok_or_internal_error(get_referrers(&image, parts[parts.len() - 1], artifact_type));
+    }
     // Request blobs upload
     else if r.uri().path().ends_with("/blobs/uploads/") {
         if user.is_none() {
-            return insufficient_authorizations(&config);
+            return insufficient_authorizations(&r, &config);
         }
 
-        return ok_or_internal_error(
-            start_blob_upload(
-                &DockerImage::new(&config.storage_path, &parts[..parts.len() - 3].join("/")),
-                &config,
-            )
-            .await,
-        );
+        let image = match requested_image(&config, &parts[..parts.len() - 3]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
+
+        if !config.is_pushable(&image.image) {
+            return push_denied();
+        }
+
+        let user = user.as_deref().unwrap_or_default();
+        return ok_or_internal_error(start_blob_upload(&image, user, &config, &state).await);
     }
     // Manage blogs upload
     else if parts[parts.len() - 3] == "blobs" && parts[parts.len() - 2] == "uploads" {
         if user.is_none() {
-            return insufficient_authorizations(&config);
+            return insufficient_authorizations(&r, &config);
         }
 
-        let image = DockerImage::new(&config.storage_path, &parts[..parts.len() - 3].join("/"));
+        let image = match requested_image(&config, &parts[..parts.len() - 3]) {
+            Ok(image) => image,
+            Err(e) => return e,
+        };
         let uuid = parts.last().unwrap_or(&"");
 
-        if !Regex::new(r"^[0-9a-zA-Z\-]+$").unwrap().is_match(uuid) {
+        if !config.is_pushable(&image.image) {
+            return push_denied();
+        }
+
+        if !UPLOAD_UUID.is_match(uuid) {
             return HttpResponse::BadRequest().json("Invalid UUID !");
         }
 
-        match *r.method() {
-            Method::GET => return ok_or_internal_error(blob_upload_status(&image, uuid, &config)),
-            Method::PATCH => return ok_or_internal_error(blob_upload_patch(&image, uuid, &config, payload).await),
-            Method::PUT => {
-                return ok_or_internal_error(
-                    blob_upload_finish(
-                        &image,
-                        uuid,
-                        &config,
-                        payload,
-                        query.digest.as_ref().unwrap_or(&String::new()),
-                    )
-                    .await,
-                )
-            }
-            Method::DELETE => return ok_or_internal_error(cancel_blob_upload(&image, uuid)),
-            _ => {}
+        // Uploads are stored with the image they were started for
+        if !image.upload_storage_path(uuid).exists() {
+            return upload_unknown();
         }
+        let _request = state.start_upload_request(uuid);
+
+        let res = match *r.method() {
+            Method::GET => ok_or_internal_error(blob_upload_status(&image, uuid, &config)),
+            Method::PATCH => ok_or_internal_error(blob_upload_patch(&image, uuid, &config, payload).await),
+            Method::PUT => ok_or_internal_error(
+                blob_upload_finish(&image, uuid, &config, &state, payload, query.digest.as_deref()).await,
+            ),
+            Method::DELETE => ok_or_internal_error(cancel_blob_upload(&image, uuid, &state)),
+            _ => return not_found().await,
+        };
+        return with_upload_uuid(res, uuid);
     }
 
     not_found().await
 }
 
-pub async fn start(config: ServerConfig) -> std::io::Result<()> {
-    let listen_address = config.listen_address.to_string();
-    HttpServer::new(move || {
+fn configure(cfg: &mut web::ServiceConfig, config: ServerConfig, state: Data<ServerState>) {
+    cfg.app_data(web::PayloadConfig::new(config.max_body_size()))
+        .app_data(Data::new(config))
+        .app_data(state)
+        .route("/token", web::get().to(get_auth_token))
+        .route("/token", web::post().to(get_auth_token))
+        .route("/token", web::head().to(probe_auth_token))
+        .route("/version", web::get().to(version))
+        .route("/v2/", web::get().to(base))
+        .route("/v2", web::get().to(base))
+        .route("/v2/_catalog", web::get().to(catalog))
+        .route("/v2/_catalog", web::head().to(head_catalog))
+        .route("/v2/_gc/status", web::get().to(gc_status))
+        .route("/v2/_usage", web::get().to(usage))
+        .route("/v2/_status", web::get().to(status))
+        .route("/v2/{tail:.*}", web::to(requests_dispatcher))
+        .route("{tail:.*}", web::to(not_found));
+}
+
+/// Run the garbage collector and drop the sizes of the removed blobs from the index
+fn periodic_gc(config: &ServerConfig, state: &ServerState) -> RegistryResult<GcStatus> {
+    state.run_gc(&config.registry_path(), config.gc_grace_period())?;
+    update_index(config, |i| {
+        i.prune(&config.registry_path());
+        Ok(())
+    })?;
+    Ok(state.gc_status())
+}
+
+/// Spawn a task running the garbage collector every `gc_interval_secs`, if configured
+///
+/// The task runs until it is aborted
+pub fn spawn_periodic_gc(config: ServerConfig, state: Data<ServerState>) -> Option<JoinHandle<()>> {
+    let period = Duration::from_secs(config.gc_interval_secs?.get());
+
+    Some(actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(period);
+
+        // The first tick completes immediately, while the storage has just been cleaned
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let (config, state) = (config.clone(), state.clone());
+            match web::block(move || periodic_gc(&config, &state)).await {
+                Ok(Ok(status)) => println!(
+                    "Periodic garbage collection completed in {} ms",
+                    status.duration_ms.unwrap_or_default()
+                ),
+                Ok(Err(e)) => eprintln!("Periodic garbage collection failed: {}", e),
+                Err(e) => eprintln!("Periodic garbage collection failed: {}", e),
+            }
+        }
+    }))
+}
+
+/// Drop a connection once the client stops reading the blobs it requested for longer than
+/// `timeout`, with an [`IdleWatchdog`] recorded in the connection data. On Linux, the kernel
+/// also drops it once the data sent to the client stays unacknowledged that long
+fn set_idle_timeout(conn: &dyn std::any::Any, ext: &mut Extensions, timeout: Duration) {
+    let Some(stream) = tls::tcp_stream(conn) else {
+        return;
+    };
+
+    match IdleWatchdog::new(stream, timeout) {
+        Ok(watchdog) => {
+            ext.insert(watchdog);
+        }
+        Err(e) => eprintln!("Failed to watch a connection: {}", e),
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Err(e) = socket2::SockRef::from(stream).set_tcp_user_timeout(Some(timeout)) {
+        eprintln!("Failed to set the idle timeout of a connection: {}", e);
+    }
+}
+
+/// Run the server on an already bound listener, over TLS when it is configured
+pub fn run_server(
+    config: ServerConfig,
+    state: Data<ServerState>,
+    listener: std::net::TcpListener,
+) -> std::io::Result<actix_web::dev::Server> {
+    let tls_config = tls::server_config(&config)?;
+    let (workers, max_connections) = (config.workers, config.max_connections);
+    let idle_timeout = config.stream_idle_timeout_secs.map(|s| Duration::from_secs(s.get()));
+
+    let mut server = HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(config.clone()))
-            .route("/token", web::to(get_auth_token))
-            .route("/v2/", web::get().to(base))
-            .route("/v2/_catalog", web::get().to(catalog))
-            .route("/v2/{tail:.*}", web::to(requests_dispatcher))
-            .route("{tail:.*}", web::to(not_found))
+            .wrap(config.extra_headers_middleware())
+            .wrap(RequestIdMiddleware)
+            .configure(|cfg| configure(cfg, config.clone(), state.clone()))
     })
-    .bind(listen_address)?
-    .run()
-    .await
+    .on_connect(move |conn, ext| {
+        tls::on_connect(conn, ext);
+        if let Some(timeout) = idle_timeout {
+            set_idle_timeout(conn, ext, timeout);
+        }
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers.get());
+    }
+    if let Some(max_connections) = max_connections {
+        server = server.max_connections(max_connections.get());
+    }
+
+    let server = match tls_config {
+        Some(tls_config) => server.listen_rustls_0_21(listener, tls_config)?,
+        None => server.listen(listener)?,
+    };
+
+    Ok(server.run())
 }
+
+pub async fn start(config: ServerConfig, state: ServerState) -> std::io::Result<()> {
+    let listener = std::net::TcpListener::bind(&config.listen_address)?;
+    let state = Data::new(state);
+    let gc_task = spawn_periodic_gc(config.clone(), state.clone());
+
+    let res = match run_server(config, state, listener) {
+        Ok(server) => server.await,
+        Err(e) => Err(e),
+    };
+
+    if let Some(gc_task) = gc_task {
+        gc_task.abort();
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests;
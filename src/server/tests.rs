@@ -0,0 +1,3212 @@
+use std::path::Path;
+
+use actix_http::Request;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::StatusCode;
+use actix_web::test;
+
+use super::*;
+use crate::docker::{DOCKER_MANIFEST_V1_SIGNED, DOCKER_MANIFEST_V2, OCI_IMAGE_INDEX_V1, OCI_IMAGE_MANIFEST_V1};
+use crate::request_id::RequestIdMiddleware;
+use crate::utils::{sha256sum_str, write_file_atomic};
+
+/// Initialize a test service of the registry
+macro_rules! init_app {
+    ($config:expr) => {{
+        let config: ServerConfig = $config;
+        test::init_service(
+            App::new()
+                .wrap(config.extra_headers_middleware())
+                .wrap(RequestIdMiddleware)
+                .configure(|cfg| configure(cfg, config, Data::default())),
+        )
+        .await
+    }};
+}
+
+const TEST_USER: &str = "user";
+const TEST_PASSWORD: &str = "password";
+
+fn test_config(storage: &Path) -> ServerConfig {
+    ServerConfig {
+        credentials: vec![Credentials {
+            user_name: TEST_USER.to_string(),
+            password_hash: bcrypt::hash(TEST_PASSWORD, 4).unwrap(),
+            admin: false,
+        }],
+        ..ServerConfig::for_test(storage)
+    }
+}
+
+/// Configuration of a registry that does not require authentication
+fn open_config(storage: &Path) -> ServerConfig {
+    ServerConfig::for_test(storage)
+}
+
+/// Root of the registry of a test storage
+fn registry(storage: &Path) -> PathBuf {
+    open_config(storage).registry_path()
+}
+
+fn basic_auth(user: &str, password: &str) -> (&'static str, String) {
+    (
+        "Authorization",
+        format!(
+            "Basic {}",
+            b64decoder::STANDARD.encode(format!("{}:{}", user, password))
+        ),
+    )
+}
+
+fn bearer_auth(token: &str) -> (&'static str, String) {
+    ("Authorization", format!("Bearer {}", token))
+}
+
+fn decode_claims(conf: &ServerConfig, token: &str) -> JWTClaims {
+    jsonwebtoken::decode::<JWTClaims>(
+        token,
+        &conf.get_decoding_secret(),
+        &conf.get_auth_validation_algorithm(),
+    )
+    .unwrap()
+    .claims
+}
+
+fn sign(conf: &ServerConfig, claims: &JWTClaims) -> String {
+    encode(
+        &jsonwebtoken::Header::new(conf.jwt_algorithm()),
+        claims,
+        &conf.get_encoding_secret(),
+    )
+    .unwrap()
+}
+
+#[actix_web::test]
+async fn issued_token_is_accepted() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = test_config(&storage);
+    let app = init_app!(conf);
+
+    let req = test::TestRequest::get()
+        .uri("/token")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+
+    let req = test::TestRequest::get()
+        .uri("/v2/")
+        .insert_header(bearer_auth(&res.access_token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn token_with_foreign_issuer_is_rejected() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = test_config(&storage);
+    let token = sign(
+        &conf,
+        &JWTClaims {
+            iss: "http://other-registry".to_string(),
+            aud: conf.service_name().to_string(),
+            user: Some(TEST_USER.to_string()),
+            scope: None,
+            timeout: time() + 60,
+        },
+    );
+    let app = init_app!(conf);
+
+    let req = test::TestRequest::get()
+        .uri("/v2/")
+        .insert_header(bearer_auth(&token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn token_lifetime_is_configurable() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let mut conf = test_config(&storage);
+    conf.token_ttl_secs = Some(42);
+    let app = init_app!(conf.clone());
+
+    let req = test::TestRequest::get()
+        .uri("/token")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.expires_in, 42);
+
+    let claims = decode_claims(&conf, &res.access_token);
+    assert!(claims.timeout <= time() + 42);
+    assert!(claims.timeout + 5 >= time() + 42);
+}
+
+#[actix_web::test]
+async fn get_token_with_query_params() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = test_config(&storage);
+    let app = init_app!(conf.clone());
+
+    let req = test::TestRequest::get()
+        .uri("/token?service=localhost&scope=repository:foo/bar:pull,push&account=user")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    let claims = decode_claims(&conf, &res.access_token);
+    assert_eq!(claims.user.as_deref(), Some(TEST_USER));
+    assert_eq!(claims.scope.as_deref(), Some("repository:foo/bar:pull,push"));
+
+    // Anonymous users are only granted pull access
+    let req = test::TestRequest::get()
+        .uri("/token?service=localhost&scope=repository:foo/bar:pull,push")
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    let claims = decode_claims(&conf, &res.access_token);
+    assert_eq!(claims.user, None);
+    assert_eq!(claims.scope.as_deref(), Some("repository:foo/bar:pull"));
+}
+
+#[actix_web::test]
+async fn tokens_can_be_requested_for_several_scopes() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = test_config(&storage);
+    let app = init_app!(conf.clone());
+
+    let req = test::TestRequest::get()
+        .uri("/token?scope=repository:foo:pull,push&scope=repository:bar:pull")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    let claims = decode_claims(&conf, &res.access_token);
+    assert_eq!(
+        claims.scope.as_deref(),
+        Some("repository:foo:pull,push repository:bar:pull")
+    );
+}
+
+#[actix_web::test]
+async fn tokens_only_grant_their_scope() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    for image in ["foo", "bar"] {
+        push_test_manifest(&init_app!(open_config(&storage)), image, "latest").await;
+    }
+    let app = init_app!(test_config(&storage));
+
+    let req = test::TestRequest::get()
+        .uri("/token?scope=repository:foo:pull")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let token = test::call_and_read_body_json::<_, _, AuthResponse>(&app, req)
+        .await
+        .access_token;
+
+    let req = test::TestRequest::get()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(bearer_auth(&token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    // Other repositories and actions need a new token, for the scope of the challenge
+    for (method, uri, scope) in [
+        (Method::GET, "/v2/bar/manifests/latest", "repository:bar:pull"),
+        (Method::PUT, "/v2/foo/manifests/latest", "repository:foo:push"),
+        (Method::POST, "/v2/foo/blobs/uploads/", "repository:foo:push"),
+    ] {
+        let req = test::TestRequest::default()
+            .method(method)
+            .uri(uri)
+            .insert_header(bearer_auth(&token))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let auth = res.headers().get("www-authenticate").unwrap().to_str().unwrap();
+        assert!(auth.contains(&format!("scope=\"{}\"", scope)), "{}", auth);
+    }
+
+    // Tokens without scope only give access to the base endpoint
+    let req = test::TestRequest::get()
+        .uri("/token")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let token = test::call_and_read_body_json::<_, _, AuthResponse>(&app, req)
+        .await
+        .access_token;
+    let req = test::TestRequest::get()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(bearer_auth(&token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn head_token_does_not_issue_tokens() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(test_config(&storage));
+
+    let req = test::TestRequest::default()
+        .method(Method::HEAD)
+        .uri("/token")
+        .insert_header(basic_auth("user", "bad password"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key("www-authenticate"));
+    assert!(test::read_body(res).await.is_empty());
+}
+
+#[actix_web::test]
+async fn get_token_rejects_mismatching_account_and_service() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(test_config(&storage));
+
+    let req = test::TestRequest::get()
+        .uri("/token?service=localhost&account=someone-else")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get().uri("/token?service=elsewhere").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn refresh_token_can_be_redeemed() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = test_config(&storage);
+    let app = init_app!(conf.clone());
+
+    let req = test::TestRequest::get()
+        .uri("/token?service=localhost&offline_token=true")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    let refresh_token = res.refresh_token.unwrap();
+
+    // A refresh token is not an auth token
+    let req = test::TestRequest::get()
+        .uri("/v2/")
+        .insert_header(bearer_auth(&refresh_token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::post()
+        .uri("/token")
+        .set_form([
+            ("grant_type", "refresh_token"),
+            ("refresh_token", &refresh_token),
+            ("service", "localhost"),
+        ])
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.refresh_token, None);
+    assert_eq!(decode_claims(&conf, &res.access_token).user.as_deref(), Some(TEST_USER));
+
+    // Auth tokens can not be used as refresh tokens
+    let req = test::TestRequest::post()
+        .uri("/token")
+        .set_form([("grant_type", "refresh_token"), ("refresh_token", &res.access_token)])
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn legacy_token_field_can_be_omitted() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+
+    for legacy_token_field in [true, false] {
+        let app = init_app!(ServerConfig {
+            legacy_token_field,
+            ..test_config(&storage)
+        });
+
+        let req = test::TestRequest::get()
+            .uri("/token")
+            .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+            .to_request();
+        let res: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(res["access_token"].is_string());
+        assert_eq!(res.get("token").is_some(), legacy_token_field);
+        if legacy_token_field {
+            assert_eq!(res["token"], res["access_token"]);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn anonymous_users_get_no_refresh_token() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(test_config(&storage));
+
+    let req = test::TestRequest::get().uri("/token?offline_token=true").to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.refresh_token, None);
+}
+
+#[actix_web::test]
+async fn anonymous_tokens_can_be_disabled() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+
+    for (disable_anonymous_tokens, status) in [(false, StatusCode::OK), (true, StatusCode::UNAUTHORIZED)] {
+        let app = init_app!(ServerConfig {
+            disable_anonymous_tokens,
+            ..test_config(&storage)
+        });
+
+        let req = test::TestRequest::get()
+            .uri("/token?scope=repository:foo:pull")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), status);
+
+        // Authenticated users still get tokens
+        let req = test::TestRequest::get()
+            .uri("/token?scope=repository:foo:pull")
+            .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    // Open registries do not need credentials
+    let app = init_app!(ServerConfig {
+        disable_anonymous_tokens: true,
+        ..open_config(&storage)
+    });
+    let req = test::TestRequest::get().uri("/token").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+const TEST_MANIFEST: &str = r#"{
+  "schemaVersion": 2,
+  "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+  "config": {
+    "mediaType": "application/vnd.docker.container.image.v1+json",
+    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+    "size": 2
+  },
+  "layers": []
+}"#;
+
+#[actix_web::test]
+async fn put_manifest_checks_content_type() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    // Mismatched
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+        .set_payload(TEST_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+    // Missing
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .set_payload(TEST_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+
+    assert!(!DockerImage::new(&registry(&storage), "foo").image_path().exists());
+
+    // Matching
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(TEST_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+}
+
+const TEST_OCI_MANIFEST: &str = r#"{
+  "schemaVersion": 2,
+  "mediaType": "application/vnd.oci.image.manifest.v1+json",
+  "config": {
+    "mediaType": "application/vnd.oci.image.config.v1+json",
+    "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+    "size": 2
+  },
+  "layers": []
+}"#;
+
+const TEST_OCI_INDEX_WITHOUT_MEDIA_TYPE: &str = r#"{
+  "schemaVersion": 2,
+  "manifests": []
+}"#;
+
+#[actix_web::test]
+async fn manifests_are_limited_by_max_body_size() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let scratch = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(ServerConfig {
+        max_body_size: Some(TEST_MANIFEST.len() - 1),
+        temp_dir: Some(scratch.to_path_buf()),
+        ..open_config(&storage)
+    });
+
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(TEST_MANIFEST)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "SIZE_INVALID");
+
+    assert!(!DockerImage::new(&registry(&storage), "foo").image_path().exists());
+    assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn manifest_content_type_matches_pushed_one() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for (tag, media_type, manifest) in [
+        ("oci", OCI_IMAGE_MANIFEST_V1, TEST_OCI_MANIFEST),
+        ("index", OCI_IMAGE_INDEX_V1, TEST_OCI_INDEX_WITHOUT_MEDIA_TYPE),
+    ] {
+        let req = test::TestRequest::put()
+            .uri(&format!("/v2/foo/manifests/{}", tag))
+            .insert_header(("Content-Type", media_type))
+            .set_payload(manifest)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        for method in [Method::GET, Method::HEAD] {
+            let req = test::TestRequest::default()
+                .method(method)
+                .uri(&format!("/v2/foo/manifests/{}", tag))
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers().get("content-type").unwrap(), media_type);
+        }
+    }
+}
+
+#[actix_web::test]
+async fn manifest_lists_are_resolved_to_requested_platform() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let mut children = vec![];
+    for (media_type, manifest) in [
+        (DOCKER_MANIFEST_V2, TEST_MANIFEST),
+        (OCI_IMAGE_MANIFEST_V1, TEST_OCI_MANIFEST),
+    ] {
+        let digest = format!("sha256:{}", sha256sum_str(manifest).unwrap());
+        push_manifest(&app, "foo", &digest, media_type, manifest).await;
+        children.push(digest);
+    }
+
+    let index = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_IMAGE_INDEX_V1,
+        "manifests": [
+            {
+                "mediaType": DOCKER_MANIFEST_V2,
+                "digest": children[0],
+                "size": TEST_MANIFEST.len(),
+                "platform": { "architecture": "amd64", "os": "linux" }
+            },
+            {
+                "mediaType": OCI_IMAGE_MANIFEST_V1,
+                "digest": children[1],
+                "size": TEST_OCI_MANIFEST.len(),
+                "platform": { "architecture": "arm64", "os": "linux", "variant": "v8" }
+            }
+        ]
+    })
+    .to_string();
+    push_manifest(&app, "foo", "latest", OCI_IMAGE_INDEX_V1, &index).await;
+
+    for (platform, media_type, manifest) in [
+        ("linux/amd64", DOCKER_MANIFEST_V2, TEST_MANIFEST),
+        ("linux/arm64", OCI_IMAGE_MANIFEST_V1, TEST_OCI_MANIFEST),
+        ("linux/arm64/v8", OCI_IMAGE_MANIFEST_V1, TEST_OCI_MANIFEST),
+    ] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/v2/foo/manifests/latest?platform={}", platform))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), media_type);
+        assert_eq!(test::read_body(res).await, manifest.as_bytes());
+    }
+
+    for platform in ["windows/amd64", "linux/arm64/v7"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/v2/foo/manifests/latest?platform={}", platform))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "MANIFEST_UNKNOWN");
+    }
+
+    // Without hint, the manifest list itself is served
+    let req = test::TestRequest::get().uri("/v2/foo/manifests/latest").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("content-type").unwrap(), OCI_IMAGE_INDEX_V1);
+    assert_eq!(test::read_body(res).await, index.as_bytes());
+}
+
+const TEST_SCHEMA1_MANIFEST: &str = r#"{
+  "schemaVersion": 1,
+  "name": "foo",
+  "tag": "latest",
+  "architecture": "amd64",
+  "fsLayers": [],
+  "history": []
+}"#;
+
+#[actix_web::test]
+async fn schema1_manifests_are_rejected() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for content_type in [DOCKER_MANIFEST_V1_SIGNED, DOCKER_MANIFEST_V2] {
+        let req = test::TestRequest::put()
+            .uri("/v2/foo/manifests/latest")
+            .insert_header(("Content-Type", content_type))
+            .set_payload(TEST_SCHEMA1_MANIFEST)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(res.headers().contains_key("warning"));
+    }
+
+    assert!(crate::storage::get_blob_list(&registry(&storage)).unwrap().is_empty());
+    assert!(!DockerImage::new(&registry(&storage), "foo").image_path().exists());
+}
+
+#[actix_web::test]
+async fn manifest_schema_version_must_be_2() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for (manifest, content_type) in [
+        (TEST_MANIFEST, DOCKER_MANIFEST_V2),
+        (TEST_OCI_INDEX_WITHOUT_MEDIA_TYPE, OCI_IMAGE_INDEX_V1),
+    ] {
+        for (version, status) in [
+            (1, StatusCode::BAD_REQUEST),
+            (3, StatusCode::BAD_REQUEST),
+            (2, StatusCode::CREATED),
+        ] {
+            let payload = manifest.replace("\"schemaVersion\": 2", &format!("\"schemaVersion\": {}", version));
+            let req = test::TestRequest::put()
+                .uri("/v2/foo/manifests/latest")
+                .insert_header(("Content-Type", content_type))
+                .set_payload(payload)
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), status);
+
+            if status == StatusCode::BAD_REQUEST {
+                let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+                assert!(body.contains("MANIFEST_INVALID"));
+            }
+        }
+    }
+}
+
+const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+const HELLO_SHA512: &str = "9b71d224bd62f3785d96d46ad3ea3d73319bfbc2890caadae2dff72519673ca72323c3d99ba5c11d7c7acc6e14b8c5da0c4663475c2e5c3adef46f73bcdec043";
+
+/// Start a blob upload and get its location
+async fn start_upload<S>(app: &S, image: &str) -> String
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let req = test::TestRequest::post()
+        .uri(&format!("/v2/{}/blobs/uploads/", image))
+        .to_request();
+    let res = test::call_service(app, req).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let location = res.headers().get("location").unwrap().to_str().unwrap();
+    location.strip_prefix("http://localhost").unwrap().to_string()
+}
+
+#[actix_web::test]
+async fn finish_upload_with_supported_algorithms() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for digest in [&format!("sha256:{}", HELLO_SHA256), &format!("sha512:{}", HELLO_SHA512)] {
+        let location = start_upload(&app, "foo").await;
+        let req = test::TestRequest::put()
+            .uri(&format!("{}?digest={}", location, digest))
+            .set_payload("hello")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+        assert!(BlobReference::from_str(digest)
+            .unwrap()
+            .data_path(&registry(&storage))
+            .exists());
+    }
+
+    let location = start_upload(&app, "foo").await;
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=md5:5d41402abc4b2a76b9719d911017c592", location))
+        .set_payload("hello")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_web::test]
+async fn durable_writes_flag_is_honored() {
+    use crate::utils::FSYNC_CALLS;
+
+    for durable_writes in [true, false] {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let conf = ServerConfig {
+            durable_writes,
+            ..open_config(&storage)
+        };
+        let app = init_app!(conf);
+
+        FSYNC_CALLS.with(|c| c.set(0));
+
+        let location = start_upload(&app, "foo").await;
+        let req = test::TestRequest::put()
+            .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+            .set_payload("hello")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::put()
+            .uri("/v2/foo/manifests/latest")
+            .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+            .set_payload(TEST_MANIFEST)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+        assert_eq!(FSYNC_CALLS.with(|c| c.get()) > 0, durable_writes);
+    }
+}
+
+#[actix_web::test]
+async fn concurrent_pushes_of_a_same_tag() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let manifests = (0..8)
+        .map(|i| TEST_MANIFEST.replace("\"size\": 2", &format!("\"size\": {}", i)))
+        .collect::<Vec<_>>();
+
+    let threads = manifests
+        .iter()
+        .cloned()
+        .map(|manifest| {
+            let conf = open_config(&storage);
+            std::thread::spawn(move || {
+                actix_web::rt::System::new().block_on(async move {
+                    let app = init_app!(conf);
+                    let req = test::TestRequest::put()
+                        .uri("/v2/foo/manifests/latest")
+                        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+                        .set_payload(manifest)
+                        .to_request();
+                    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let image = DockerImage::new(&registry(&storage), "foo");
+    let blob_ref = BlobReference::from_file(&image.manifest_tag_link_path("latest")).unwrap();
+    let manifest = std::fs::read_to_string(blob_ref.data_path(&registry(&storage))).unwrap();
+    assert!(manifests.contains(&manifest));
+    assert!(image.manifests_revision_list().unwrap().contains(&blob_ref));
+    assert_eq!(image.tags_list().unwrap(), vec!["latest".to_string()]);
+}
+
+#[actix_web::test]
+async fn concurrent_pushes_and_deletes_do_not_deadlock() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        enable_delete: true,
+        ..open_config(&storage)
+    };
+    let state = Data::new(ServerState::default());
+    let digest = format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap());
+
+    // Both requests share the state, and thus the lock of the garbage collector
+    let (done, finished) = std::sync::mpsc::channel();
+    for delete in [false, true] {
+        let (conf, state, digest, done) = (conf.clone(), state.clone(), digest.clone(), done.clone());
+        std::thread::spawn(move || {
+            actix_web::rt::System::new().block_on(async move {
+                let app = test::init_service(App::new().configure(|cfg| configure(cfg, conf, state))).await;
+                for i in 0..50 {
+                    let req = match delete {
+                        false => test::TestRequest::put()
+                            .uri(&format!("/v2/foo/manifests/v{}", i))
+                            .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+                            .set_payload(TEST_MANIFEST),
+                        true => test::TestRequest::delete().uri(&format!("/v2/foo/manifests/{}", digest)),
+                    };
+                    test::call_service(&app, req.to_request()).await;
+                }
+            });
+            done.send(()).unwrap();
+        });
+    }
+
+    for _ in 0..2 {
+        finished
+            .recv_timeout(std::time::Duration::from_secs(60))
+            .expect("requests deadlocked");
+    }
+}
+
+#[actix_web::test]
+async fn internal_errors_are_reported_as_docker_errors() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    // Corrupted tag link
+    let image = DockerImage::new(&registry(&storage), "foo");
+    crate::utils::write_file_atomic(&image.manifest_tag_link_path("latest"), "corrupted", false).unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("X-Request-Id", "my-request"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "UNKNOWN");
+    assert_eq!(body["errors"][0]["detail"]["request_id"], "my-request");
+}
+
+#[actix_web::test]
+async fn catalog_errors_are_reported_as_docker_errors() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    // The tags of a repository can not be listed if their directory is a file
+    let image = DockerImage::new(&registry(&storage), "foo");
+    std::fs::create_dir_all(image.revisions_path()).unwrap();
+    std::fs::write(image.tags_path(), "").unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/v2/_catalog?extended=true")
+        .insert_header(("X-Request-Id", "my-request"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"].as_array().unwrap().len(), 1);
+    assert_eq!(body["errors"][0]["code"], "UNKNOWN");
+    assert_eq!(body["errors"][0]["detail"]["request_id"], "my-request");
+}
+
+#[actix_web::test]
+async fn oversized_bodies_are_rejected() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        max_body_size: Some(100),
+        ..test_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    let req = test::TestRequest::post()
+        .uri("/token")
+        .set_form([("grant_type", "refresh_token"), ("refresh_token", &"a".repeat(200))])
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+}
+
+#[actix_web::test]
+async fn base_endpoint_returns_empty_json_object() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for uri in ["/v2/", "/v2"] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(), "application/json");
+        assert_eq!(
+            res.headers().get("docker-distribution-api-version").unwrap(),
+            "registry/2.0"
+        );
+        assert_eq!(test::read_body(res).await, "{}");
+    }
+
+    // Authentication is required on both routes
+    let app = init_app!(test_config(&storage));
+    for uri in ["/v2/", "/v2"] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[actix_web::test]
+async fn push_and_pull_with_custom_base_path() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        storage_base_path: Some(PathBuf::from("custom")),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    // Push config blob
+    let location = start_upload(&app, "foo").await;
+    let config_digest = "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest={}", location, config_digest))
+        .set_payload("{}")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    // Push manifest
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(TEST_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    // Pull
+    let req = test::TestRequest::get().uri("/v2/foo/manifests/latest").to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, TEST_MANIFEST);
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/{}", config_digest))
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, "{}");
+
+    let root = storage.join("custom");
+    assert!(DockerImage::new(&root, "foo").manifest_tag_link_path("latest").exists());
+    assert!(BlobReference::from_str(config_digest)
+        .unwrap()
+        .data_path(&root)
+        .exists());
+    assert!(!registry(&storage).exists());
+}
+
+#[actix_web::test]
+async fn image_names_are_checked() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let at_limit = format!("foo/{}", "a".repeat(251));
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/{}/tags/list", at_limit))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    for name in [
+        format!("foo/{}", "a".repeat(252)),
+        vec!["a"; 17].join("/"),
+        "Foo".to_string(),
+        "foo/../bar".to_string(),
+    ] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/v2/{}/tags/list", name))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", name);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "NAME_INVALID");
+    }
+}
+
+/// Push the test manifest under a given tag
+async fn push_manifest<S>(app: &S, image: &str, reference: &str, media_type: &str, manifest: &str)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let req = test::TestRequest::put()
+        .uri(&format!("/v2/{}/manifests/{}", image, reference))
+        .insert_header(("Content-Type", media_type))
+        .set_payload(manifest.to_string())
+        .to_request();
+    assert_eq!(test::call_service(app, req).await.status(), StatusCode::CREATED);
+}
+
+async fn push_test_manifest<S>(app: &S, image: &str, tag: &str)
+where
+    S: Service<Request, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    push_manifest(app, image, tag, DOCKER_MANIFEST_V2, TEST_MANIFEST).await;
+}
+
+#[actix_web::test]
+async fn head_requests_on_catalog_and_tags() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    for (uri, status) in [
+        ("/v2/_catalog", StatusCode::OK),
+        ("/v2/foo/tags/list", StatusCode::OK),
+        ("/v2/bar/tags/list", StatusCode::NOT_FOUND),
+    ] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        let get = test::call_service(&app, req).await;
+        let get_headers = get.headers().clone();
+        let get_len = test::read_body(get).await.len();
+
+        let req = test::TestRequest::default().method(Method::HEAD).uri(uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), status, "{}", uri);
+        assert_eq!(res.headers().get("content-type"), get_headers.get("content-type"));
+        assert_eq!(res.headers().get("content-length").unwrap(), &get_len.to_string());
+        assert!(test::read_body(res).await.is_empty());
+    }
+}
+
+#[actix_web::test]
+async fn head_tag_reports_existence() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    let head = |uri: &str| test::TestRequest::default().method(Method::HEAD).uri(uri).to_request();
+
+    let res = test::call_service(&app, head("/v2/foo/tags/latest")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get("docker-content-digest").unwrap(),
+        &format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap())
+    );
+    assert!(test::read_body(res).await.is_empty());
+
+    for uri in ["/v2/foo/tags/missing", "/v2/bar/tags/latest"] {
+        let res = test::call_service(&app, head(uri)).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND, "{}", uri);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "MANIFEST_UNKNOWN");
+    }
+}
+
+#[actix_web::test]
+async fn catalog_reports_total_count() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let req = test::TestRequest::get().uri("/v2/_catalog").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("x-total-count").unwrap(), "0");
+
+    for image in ["foo", "bar", "baz/qux"] {
+        push_test_manifest(&app, image, "latest").await;
+    }
+
+    for uri in ["/v2/_catalog", "/v2/_catalog?n=1", "/v2/_catalog?n=2&last=bar"] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(res.headers().get("x-total-count").unwrap(), "3");
+    }
+}
+
+#[actix_web::test]
+async fn catalog_pagination_edge_cases() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for image in ["foo", "bar", "baz/qux"] {
+        push_test_manifest(&app, image, "latest").await;
+    }
+
+    for (uri, expected, next) in [
+        ("/v2/_catalog?n=0", vec![], None),
+        (
+            "/v2/_catalog?n=2",
+            vec!["bar", "baz/qux"],
+            Some("</v2/_catalog?n=2&last=baz/qux>; rel=\"next\""),
+        ),
+        ("/v2/_catalog?n=2&last=bar", vec!["baz/qux", "foo"], None),
+        ("/v2/_catalog?n=3", vec!["bar", "baz/qux", "foo"], None),
+        ("/v2/_catalog?n=1000000", vec!["bar", "baz/qux", "foo"], None),
+        (
+            "/v2/_catalog?n=18446744073709551615&last=baz",
+            vec!["baz/qux", "foo"],
+            None,
+        ),
+        ("/v2/_catalog?last=foo", vec![], None),
+    ] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("link").map(|l| l.to_str().unwrap()), next, "{}", uri);
+        let catalog: DockerCatalog = test::read_body_json(res).await;
+        assert_eq!(catalog.repositories, expected, "{}", uri);
+    }
+}
+
+#[actix_web::test]
+async fn catalog_pages_are_capped() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        catalog_max_page_size: NonZeroUsize::new(2),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    for image in ["foo", "bar", "baz/qux"] {
+        push_test_manifest(&app, image, "latest").await;
+    }
+
+    for uri in ["/v2/_catalog", "/v2/_catalog?n=1000"] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(
+            res.headers().get("link").unwrap(),
+            "</v2/_catalog?n=2&last=baz/qux>; rel=\"next\""
+        );
+        let catalog: DockerCatalog = test::read_body_json(res).await;
+        assert_eq!(catalog.repositories, vec!["bar", "baz/qux"]);
+    }
+}
+
+#[actix_web::test]
+async fn immutable_tags_can_not_be_overwritten() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        immutable_tags: true,
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    push_test_manifest(&app, "foo", "v1").await;
+
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/v1")
+        .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+        .set_payload(TEST_OCI_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CONFLICT);
+
+    // Pushing the same manifest again leaves the tag where it is
+    push_test_manifest(&app, "foo", "v1").await;
+
+    // New tags and digests can still be pushed
+    push_test_manifest(&app, "foo", "v2").await;
+    let digest = format!("sha256:{}", sha256sum_str(TEST_OCI_MANIFEST).unwrap());
+    let req = test::TestRequest::put()
+        .uri(&format!("/v2/foo/manifests/{}", digest))
+        .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+        .set_payload(TEST_OCI_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let image = DockerImage::new(&registry(&storage), "foo");
+    let v1 = BlobReference::from_file(&image.manifest_tag_link_path("v1")).unwrap();
+    assert_eq!(
+        v1.to_digest(),
+        format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap())
+    );
+}
+
+#[actix_web::test]
+async fn immutable_tag_patterns_protect_matching_tags() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        immutable_tag_patterns: vec!["v*".to_string()],
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    for tag in ["v1.0.0", "latest"] {
+        push_test_manifest(&app, "foo", tag).await;
+    }
+
+    let overwrite = |tag: &str| {
+        test::TestRequest::put()
+            .uri(&format!("/v2/foo/manifests/{}", tag))
+            .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+            .set_payload(TEST_OCI_MANIFEST)
+            .to_request()
+    };
+    assert_eq!(
+        test::call_service(&app, overwrite("v1.0.0")).await.status(),
+        StatusCode::CONFLICT
+    );
+    assert_eq!(
+        test::call_service(&app, overwrite("latest")).await.status(),
+        StatusCode::CREATED
+    );
+}
+
+#[actix_web::test]
+async fn manifest_put_honors_if_match() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    let docker_digest = format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap());
+    let oci_digest = format!("sha256:{}", sha256sum_str(TEST_OCI_MANIFEST).unwrap());
+    let put = |tag: &str, if_match: Option<&str>| {
+        let mut req = test::TestRequest::put()
+            .uri(&format!("/v2/foo/manifests/{}", tag))
+            .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+            .set_payload(TEST_OCI_MANIFEST);
+        if let Some(if_match) = if_match {
+            req = req.insert_header(("If-Match", if_match));
+        }
+        req.to_request()
+    };
+    let tag_digest = |tag: &str| {
+        BlobReference::from_file(&DockerImage::new(&registry(&storage), "foo").manifest_tag_link_path(tag))
+            .unwrap()
+            .to_digest()
+    };
+
+    // Mismatched precondition, or missing tag
+    for (tag, if_match) in [("latest", oci_digest.as_str()), ("missing", "*")] {
+        let res = test::call_service(&app, put(tag, Some(if_match))).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_FAILED);
+    }
+    assert_eq!(tag_digest("latest"), docker_digest);
+
+    // Matching precondition, quoted as an entity tag
+    let if_match = format!("\"{}\"", docker_digest);
+    let res = test::call_service(&app, put("latest", Some(&if_match))).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(tag_digest("latest"), oci_digest);
+
+    // No precondition
+    let res = test::call_service(&app, put("other", None)).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn pushes_are_restricted_to_pushable_namespaces() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+
+    // Started before the restriction, to check uploads in progress
+    let upload = start_upload(&init_app!(open_config(&storage)), "team-b/app").await;
+    crate::storage::store_manifest(&registry(&storage), "team-b/app", "latest", TEST_MANIFEST.as_bytes()).unwrap();
+
+    let conf = ServerConfig {
+        pushable_namespaces: vec!["team-a/*".to_string()],
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    push_test_manifest(&app, "team-a/app", "latest").await;
+    start_upload(&app, "team-a/app").await;
+
+    for req in [
+        test::TestRequest::put()
+            .uri("/v2/team-b/app/manifests/latest")
+            .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+            .set_payload(TEST_MANIFEST),
+        test::TestRequest::post().uri("/v2/team-b/app/blobs/uploads/"),
+        test::TestRequest::patch().uri(&upload).set_payload("hello"),
+    ] {
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "DENIED");
+    }
+
+    for image in ["team-a/app", "team-b/app"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/v2/{}/manifests/latest", image))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
+
+#[actix_web::test]
+async fn registry_errors_are_mapped_to_status_codes() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for (uri, status, code) in [
+        ("/v2/foo/blobs/nope", StatusCode::BAD_REQUEST, "DIGEST_INVALID"),
+        (
+            "/v2/foo/blobs/sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
+            StatusCode::NOT_FOUND,
+            "BLOB_UNKNOWN",
+        ),
+        ("/v2/foo/manifests/latest", StatusCode::NOT_FOUND, "MANIFEST_UNKNOWN"),
+    ] {
+        let res = test::call_service(&app, test::TestRequest::get().uri(uri).to_request()).await;
+        assert_eq!(res.status(), status, "{}", uri);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], code);
+    }
+}
+
+#[actix_web::test]
+async fn put_manifest_by_digest_is_verified() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let req = test::TestRequest::put()
+        .uri(&format!("/v2/foo/manifests/sha256:{}", HELLO_SHA256))
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(TEST_MANIFEST)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "DIGEST_INVALID");
+    assert!(crate::storage::get_blob_list(&registry(&storage)).unwrap().is_empty());
+
+    let digest = format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap());
+    let req = test::TestRequest::put()
+        .uri(&format!("/v2/foo/manifests/{}", digest))
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(TEST_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn corrupted_manifests_are_repaired_on_push() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    // Same size as the manifest, so that only its digest tells it apart
+    let blob_ref = BlobReference::from_sha256sum(sha256sum_str(TEST_MANIFEST).unwrap());
+    let blob_path = blob_ref.data_path(&registry(&storage));
+    std::fs::write(&blob_path, "x".repeat(TEST_MANIFEST.len())).unwrap();
+
+    push_test_manifest(&app, "foo", "latest").await;
+    assert_eq!(std::fs::read_to_string(&blob_path).unwrap(), TEST_MANIFEST);
+
+    let req = test::TestRequest::get().uri("/v2/foo/manifests/latest").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(test::read_body(res).await, TEST_MANIFEST.as_bytes());
+}
+
+#[actix_web::test]
+async fn concurrent_uploads_are_limited() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        max_concurrent_uploads: Some(2),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    let first = start_upload(&app, "foo").await;
+    start_upload(&app, "foo").await;
+
+    let req = test::TestRequest::post().uri("/v2/foo/blobs/uploads/").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(res.headers().contains_key("retry-after"));
+
+    // Ending an upload frees a slot
+    let req = test::TestRequest::delete().uri(&first).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+    start_upload(&app, "foo").await;
+}
+
+#[actix_web::test]
+async fn failed_uploads_release_their_slot() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(ServerConfig {
+        max_concurrent_uploads: Some(1),
+        ..open_config(&storage)
+    });
+    let finish = |location: &str, query: &str| {
+        test::TestRequest::put()
+            .uri(&format!("{}{}", location, query))
+            .set_payload("hello")
+            .to_request()
+    };
+
+    // Finalizing without a digest can be retried, the data is kept
+    let retried = start_upload(&app, "foo").await;
+    assert_eq!(
+        test::call_service(&app, finish(&retried, "")).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    let req = test::TestRequest::get().uri(&retried).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+
+    // Data not matching its digest is dropped
+    let mismatch = start_upload(&app, "foo").await;
+    let query = "?digest=sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    assert_eq!(
+        test::call_service(&app, finish(&mismatch, query)).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+    let req = test::TestRequest::get().uri(&mismatch).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let query = format!("?digest=sha256:{}", HELLO_SHA256);
+    assert_eq!(
+        test::call_service(&app, finish(&retried, &query)).await.status(),
+        StatusCode::CREATED
+    );
+    start_upload(&app, "foo").await;
+}
+
+#[actix_web::test]
+async fn idle_uploads_expire() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(ServerConfig {
+        max_concurrent_uploads: Some(1),
+        upload_idle_timeout_secs: Some(1),
+        ..open_config(&storage)
+    });
+
+    let abandoned = start_upload(&app, "foo").await;
+    let req = test::TestRequest::post().uri("/v2/foo/blobs/uploads/").to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    std::thread::sleep(Duration::from_millis(1100));
+    start_upload(&app, "foo").await;
+
+    let req = test::TestRequest::get().uri(&abandoned).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn uploads_do_not_expire_while_receiving_data() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let state = Data::new(crate::state::ServerState::default());
+    let app =
+        test::init_service(App::new().configure(|cfg| configure(cfg, open_config(&storage), state.clone()))).await;
+
+    let location = start_upload(&app, "foo").await;
+    let uuid = location.rsplit('/').next().unwrap();
+    let path = DockerImage::new(&registry(&storage), "foo").upload_storage_path(uuid);
+
+    // A request streaming a large body for longer than the idle timeout
+    let request = state.start_upload_request(uuid);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(state.expire_uploads(Duration::from_millis(50)), 0);
+    assert!(path.exists());
+
+    // The upload is idle again once the request ends
+    drop(request);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(state.expire_uploads(Duration::from_millis(50)), 1);
+    assert!(!path.exists());
+}
+
+#[actix_web::test]
+async fn concurrent_uploads_are_limited_per_user() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        max_concurrent_uploads_per_user: Some(1),
+        ..open_config(&storage)
+    };
+    let state = Data::new(crate::state::ServerState::default());
+    let app = test::init_service(App::new().configure(|cfg| configure(cfg, conf, state.clone()))).await;
+
+    start_upload(&app, "foo").await;
+    let req = test::TestRequest::post().uri("/v2/foo/blobs/uploads/").to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+
+    // Other users are not affected
+    assert!(state.try_start_upload("uuid", storage.join("upload"), "someone-else", None, Some(1)));
+}
+
+#[actix_web::test]
+async fn gc_status_reflects_last_run() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = open_config(&storage);
+    let state = Data::new(crate::state::ServerState::default());
+    let app = test::init_service(App::new().configure(|cfg| configure(cfg, conf.clone(), state.clone()))).await;
+
+    let req = test::TestRequest::get().uri("/v2/_gc/status").to_request();
+    let status: crate::state::GcStatus = test::call_and_read_body_json(&app, req).await;
+    assert!(status.last_run.is_none());
+
+    // Deleting a manifest triggers the garbage collector
+    push_test_manifest(&app, "foo", "latest").await;
+    let digest = format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap());
+    let before = time();
+    let req = test::TestRequest::delete()
+        .uri(&format!("/v2/foo/manifests/{}", digest))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+
+    let req = test::TestRequest::get().uri("/v2/_gc/status").to_request();
+    let status: crate::state::GcStatus = test::call_and_read_body_json(&app, req).await;
+    assert!(status.last_run.unwrap() >= before);
+    assert!(status.duration_ms.is_some());
+}
+
+#[actix_web::test]
+async fn gc_status_requires_an_admin() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(test_config(&storage));
+
+    let req = test::TestRequest::get().uri("/v2/_gc/status").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    // Authenticated users are not enough
+    let req = test::TestRequest::get()
+        .uri("/token")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    let req = test::TestRequest::get()
+        .uri("/v2/_gc/status")
+        .insert_header(bearer_auth(&res.access_token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let mut conf = test_config(&storage);
+    conf.credentials.push(Credentials {
+        user_name: "admin".to_string(),
+        password_hash: bcrypt::hash(TEST_PASSWORD, 4).unwrap(),
+        admin: true,
+    });
+    let app = init_app!(conf);
+    let req = test::TestRequest::get()
+        .uri("/token")
+        .insert_header(basic_auth("admin", TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    let req = test::TestRequest::get()
+        .uri("/v2/_gc/status")
+        .insert_header(bearer_auth(&res.access_token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn status_summarizes_the_registry() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for image in ["foo", "bar", "library/baz"] {
+        push_test_manifest(&app, image, "latest").await;
+    }
+
+    let req = test::TestRequest::get().uri("/v2/_status").to_request();
+    let status: RegistryStatus = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(status.repositories, 3);
+    assert_eq!(status.blobs, 1);
+    assert_eq!(status.total_bytes, TEST_MANIFEST.len() as u64);
+    assert!(status.last_gc_at.is_none());
+
+    // Only administrators can get the status
+    let app = init_app!(test_config(&storage));
+    let req = test::TestRequest::get().uri("/v2/_status").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+
+    let req = test::TestRequest::get()
+        .uri("/token")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    let req = test::TestRequest::get()
+        .uri("/v2/_status")
+        .insert_header(bearer_auth(&res.access_token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn status_uses_the_size_index() {
+    use crate::index::{IndexBackend, SizeIndex};
+
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        index_backend: Some(IndexBackend::Json),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+    push_test_manifest(&app, "foo", "latest").await;
+
+    // The status reflects the index rather than the storage
+    SizeIndex::update(&registry(&storage), |i| {
+        i.blobs.insert(format!("sha256:{}", "0".repeat(64)), 10);
+        Ok(())
+    })
+    .unwrap();
+
+    let req = test::TestRequest::get().uri("/v2/_status").to_request();
+    let status: RegistryStatus = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(status.blobs, 2);
+    assert_eq!(status.total_bytes, TEST_MANIFEST.len() as u64 + 10);
+}
+
+#[actix_web::test]
+async fn extra_headers_are_added_to_responses() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        extra_headers: BTreeMap::from([
+            ("X-Content-Type-Options".to_string(), "nosniff".to_string()),
+            ("Server".to_string(), "registry".to_string()),
+            // Headers set by the handlers are kept
+            ("Docker-Distribution-API-Version".to_string(), "overridden".to_string()),
+        ]),
+        ..open_config(&storage)
+    };
+    assert!(conf.check_extra_headers().is_ok());
+    let app = init_app!(conf);
+
+    let req = test::TestRequest::get().uri("/v2/").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("x-content-type-options").unwrap(), "nosniff");
+    assert_eq!(res.headers().get("server").unwrap(), "registry");
+    assert_eq!(
+        res.headers().get("docker-distribution-api-version").unwrap(),
+        "registry/2.0"
+    );
+
+    for (name, value) in [("Bad Name", "value"), ("X-Header", "bad\nvalue")] {
+        let conf = ServerConfig {
+            extra_headers: BTreeMap::from([(name.to_string(), value.to_string())]),
+            ..open_config(&storage)
+        };
+        assert!(conf.check_extra_headers().is_err(), "{}", name);
+    }
+}
+
+#[actix_web::test]
+async fn invalid_listen_address_is_rejected() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+
+    for addr in ["127.0.0.1:45654", "[::1]:45654", "localhost:45654"] {
+        let conf = ServerConfig {
+            listen_address: addr.to_string(),
+            ..open_config(&storage)
+        };
+        assert!(conf.check_listen_address().is_ok(), "{}", addr);
+    }
+
+    for addr in ["127.0.0.1;45654", "127.0.0.1", "127.0.0.1:99999", ":45654"] {
+        let conf = ServerConfig {
+            listen_address: addr.to_string(),
+            ..open_config(&storage)
+        };
+        let err = conf.check_listen_address().unwrap_err();
+        assert!(err.contains(addr), "{}", err);
+        assert!(err.contains("<ip>:<port>"), "{}", err);
+    }
+}
+
+#[actix_web::test]
+async fn small_blobs_are_served_with_content_length() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let location = start_upload(&app, "foo").await;
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+        .set_payload("hello")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let source = BlobReference::from_str(&format!("sha256:{}", HELLO_SHA256))
+        .unwrap()
+        .data_path(&registry(&storage));
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/sha256:{}", HELLO_SHA256))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        actix_web::body::MessageBody::size(res.response().body()),
+        actix_web::body::BodySize::Sized(5)
+    );
+    assert_eq!(test::read_body(res).await, std::fs::read(source).unwrap());
+}
+
+#[actix_web::test]
+async fn request_ids_are_echoed_or_generated() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let req = test::TestRequest::get()
+        .uri("/v2/")
+        .insert_header(("X-Request-Id", "my-request"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("x-request-id").unwrap(), "my-request");
+
+    let req = test::TestRequest::get().uri("/v2/").to_request();
+    let res = test::call_service(&app, req).await;
+    let id = res.headers().get("x-request-id").unwrap().to_str().unwrap();
+    assert!(Uuid::parse_str(id).is_ok());
+}
+
+#[actix_web::test]
+async fn request_ids_are_current_while_handling_requests() {
+    use crate::request_id::current;
+
+    let app = test::init_service(App::new().wrap(RequestIdMiddleware).route(
+        "/",
+        web::get().to(|| async {
+            let before = current();
+            actix_web::rt::task::yield_now().await;
+            format!("{:?} {:?}", before, current())
+        }),
+    ))
+    .await;
+
+    let req = test::TestRequest::get()
+        .uri("/")
+        .insert_header(("X-Request-Id", "my-request"))
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    assert_eq!(body, r#"Some("my-request") Some("my-request")"#);
+    assert_eq!(current(), None);
+}
+
+#[actix_web::test]
+async fn head_blob_checks_existence() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let uri = format!("/v2/foo/blobs/sha256:{}", HELLO_SHA256);
+    let req = test::TestRequest::default().method(Method::HEAD).uri(&uri).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "BLOB_UNKNOWN");
+
+    let location = start_upload(&app, "foo").await;
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+        .set_payload("hello")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let req = test::TestRequest::default().method(Method::HEAD).uri(&uri).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("content-length").unwrap(), "5");
+    assert_eq!(
+        res.headers().get("docker-content-digest").unwrap().to_str().unwrap(),
+        format!("sha256:{}", HELLO_SHA256)
+    );
+}
+
+#[actix_web::test]
+async fn tokens_signed_with_previous_secrets_are_accepted() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let old_conf = test_config(&storage);
+    let claims = JWTClaims {
+        iss: old_conf.access_url.to_string(),
+        aud: old_conf.service_name().to_string(),
+        user: Some(TEST_USER.to_string()),
+        scope: None,
+        timeout: time() + 60,
+    };
+    let old_token = sign(&old_conf, &claims);
+
+    let conf = ServerConfig {
+        app_secret: "new-secret".to_string(),
+        previous_app_secrets: vec![old_conf.app_secret.to_string()],
+        ..test_config(&storage)
+    };
+    let app = init_app!(conf.clone());
+
+    let req = test::TestRequest::get()
+        .uri("/v2/")
+        .insert_header(bearer_auth(&old_token))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    // New tokens are signed with the current secret
+    let req = test::TestRequest::get()
+        .uri("/token")
+        .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+        .to_request();
+    let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+    decode_claims(&conf, &res.access_token);
+
+    // Unknown secrets are still rejected
+    let foreign = sign(
+        &ServerConfig {
+            app_secret: "unknown".to_string(),
+            ..test_config(&storage)
+        },
+        &claims,
+    );
+    let req = test::TestRequest::get()
+        .uri("/v2/")
+        .insert_header(bearer_auth(&foreign))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_web::test]
+async fn manifests_with_too_many_layers_are_rejected() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        max_manifest_layers: Some(10),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    let mut manifest: serde_json::Value = serde_json::from_str(TEST_MANIFEST).unwrap();
+    let layer = serde_json::json!({
+        "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+        "size": 5,
+        "digest": format!("sha256:{}", HELLO_SHA256),
+    });
+    manifest["layers"] = serde_json::Value::Array(vec![layer; 1000]);
+
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(manifest.to_string())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "MANIFEST_INVALID");
+}
+
+#[actix_web::test]
+async fn upload_status_reports_received_bytes() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let location = start_upload(&app, "foo").await;
+    for chunk in ["hel", "lo"] {
+        let req = test::TestRequest::patch()
+            .uri(&location)
+            .set_payload(chunk)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+    }
+
+    let req = test::TestRequest::get().uri(&location).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers().get("range").unwrap(), "0-4");
+
+    // All the received bytes make up the blob
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+}
+
+#[actix_web::test]
+async fn uploads_are_resumed_after_a_restart() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+
+    let app = init_app!(open_config(&storage));
+    let location = start_upload(&app, "foo").await;
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .set_payload("hel")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+    drop(app);
+
+    // The state of the server is lost, only the partial upload remains
+    let app = init_app!(open_config(&storage));
+    let req = test::TestRequest::get().uri(&location).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers().get("range").unwrap(), "0-2");
+
+    let req = test::TestRequest::patch().uri(&location).set_payload("lo").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("range").unwrap(), "0-4");
+
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(
+        res.headers().get("docker-content-digest").unwrap(),
+        &format!("sha256:{}", HELLO_SHA256)
+    );
+
+    let blob = BlobReference::from_sha256sum(HELLO_SHA256.to_string());
+    assert_eq!(
+        std::fs::read_to_string(blob.data_path(&registry(&storage))).unwrap(),
+        "hello"
+    );
+}
+
+#[actix_web::test]
+async fn uploads_are_complete_with_a_large_buffer() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(ServerConfig {
+        upload_buffer_bytes: Some(crate::constants::MAX_UPLOAD_BUFFER_SIZE),
+        ..open_config(&storage)
+    });
+
+    // Chunks smaller than the buffer, which must still all be on the disk between requests
+    let chunks = ["a".repeat(3 * 1024 * 1024), "b".repeat(1024 * 1024 + 7)];
+    let content = chunks.concat();
+    let location = start_upload(&app, "foo").await;
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .set_payload(chunks[0].clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+
+    let req = test::TestRequest::get().uri(&location).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        res.headers().get("range").unwrap().to_str().unwrap(),
+        format!("0-{}", chunks[0].len() - 1)
+    );
+
+    let digest = format!("sha256:{}", sha256sum_str(&content).unwrap());
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest={}", location, digest))
+        .set_payload(chunks[1].clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let blob: BlobReference = digest.parse().unwrap();
+    assert_eq!(
+        std::fs::read_to_string(blob.data_path(&registry(&storage))).unwrap(),
+        content
+    );
+}
+
+#[actix_web::test]
+async fn blob_referrers_lists_tags_and_manifests() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let config_digest = "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+    for (digest, content) in [
+        (config_digest.to_string(), "{}"),
+        (format!("sha256:{}", HELLO_SHA256), "hello"),
+    ] {
+        let location = start_upload(&app, "foo").await;
+        let req = test::TestRequest::put()
+            .uri(&format!("{}?digest={}", location, digest))
+            .set_payload(content)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    }
+
+    // Two manifests sharing the same config
+    push_test_manifest(&app, "foo", "a").await;
+    let other = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": DOCKER_MANIFEST_V2,
+        "config": {"mediaType": "application/vnd.docker.container.image.v1+json", "digest": config_digest, "size": 2},
+        "layers": [{
+            "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+            "digest": format!("sha256:{}", HELLO_SHA256),
+            "size": 5,
+        }],
+    })
+    .to_string();
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/b")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(other.clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/{}/_refs", config_digest))
+        .to_request();
+    let refs: crate::api::DockerBlobReferrers = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(refs.tags, vec!["a", "b"]);
+    let mut expected = vec![
+        format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap()),
+        format!("sha256:{}", sha256sum_str(&other).unwrap()),
+    ];
+    expected.sort();
+    assert_eq!(refs.manifests, expected);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/sha256:{}/_refs", HELLO_SHA256))
+        .to_request();
+    let refs: crate::api::DockerBlobReferrers = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(refs.tags, vec!["b"]);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/sha256:{}/_refs", "0".repeat(64)))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn blobs_can_be_deleted_once_unreferenced() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let config_uri = "/v2/foo/blobs/sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+    let hello_uri = format!("/v2/foo/blobs/sha256:{}", HELLO_SHA256);
+
+    // Deletion is disabled by default
+    let app = init_app!(open_config(&storage));
+    let req = test::TestRequest::delete().uri(&hello_uri).to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+
+    let conf = ServerConfig {
+        enable_delete: true,
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    // Missing
+    let req = test::TestRequest::delete().uri(&hello_uri).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "BLOB_UNKNOWN");
+
+    for (uri, content) in [(config_uri.to_string(), "{}"), (hello_uri.clone(), "hello")] {
+        let location = start_upload(&app, "foo").await;
+        let digest = uri.rsplit('/').next().unwrap();
+        let req = test::TestRequest::put()
+            .uri(&format!("{}?digest={}", location, digest))
+            .set_payload(content)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    }
+    push_test_manifest(&app, "foo", "latest").await;
+
+    // Referenced
+    let req = test::TestRequest::delete().uri(config_uri).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "DENIED");
+
+    // Orphaned
+    let req = test::TestRequest::delete().uri(&hello_uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+    let req = test::TestRequest::get().uri(&hello_uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn size_index_stays_consistent() {
+    use crate::index::{IndexBackend, SizeIndex};
+
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        index_backend: Some(IndexBackend::Json),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf.clone());
+    let root = registry(&storage);
+
+    // Push
+    let location = start_upload(&app, "foo").await;
+    let req = test::TestRequest::put()
+        .uri(&format!(
+            "{}?digest=sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+            location
+        ))
+        .set_payload("{}")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    push_test_manifest(&app, "foo", "latest").await;
+
+    let index = SizeIndex::load(&root).unwrap();
+    assert_eq!(index, SizeIndex::rebuild(&root).unwrap());
+    assert_eq!(index.images["foo"], 2 + TEST_MANIFEST.len() as u64);
+
+    let req = test::TestRequest::get().uri("/v2/_usage").to_request();
+    let usage: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(usage["total"], index.total_size());
+
+    // Delete, which runs the garbage collector
+    let req = test::TestRequest::delete()
+        .uri(&format!(
+            "/v2/foo/manifests/sha256:{}",
+            sha256sum_str(TEST_MANIFEST).unwrap()
+        ))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+
+    let index = SizeIndex::load(&root).unwrap();
+    assert_eq!(index, SizeIndex::rebuild(&root).unwrap());
+    assert!(index.images.is_empty());
+
+    // Rebuild after a drift
+    push_test_manifest(&app, "foo", "latest").await;
+    let expected = SizeIndex::load(&root).unwrap();
+    SizeIndex::default().save(&root).unwrap();
+    SizeIndex::rebuild(&root).unwrap().save(&root).unwrap();
+    assert_eq!(SizeIndex::load(&root).unwrap(), expected);
+}
+
+#[actix_web::test]
+async fn finishing_an_upload_requires_a_digest() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    for query in ["", "?digest="] {
+        let location = start_upload(&app, "foo").await;
+        let req = test::TestRequest::put()
+            .uri(&format!("{}{}", location, query))
+            .set_payload("hello")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "DIGEST_INVALID");
+        assert!(body["errors"][0]["message"].as_str().unwrap().contains("required"));
+    }
+}
+
+#[actix_web::test]
+async fn uploads_can_be_finalized_with_an_empty_put() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let location = start_upload(&app, "foo").await;
+    for chunk in ["hel", "lo"] {
+        let req = test::TestRequest::patch()
+            .uri(&location)
+            .set_payload(chunk)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+    }
+
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers().get("content-range").unwrap(), "0-4");
+
+    let blob = BlobReference::from_str(&format!("sha256:{}", HELLO_SHA256)).unwrap();
+    assert_eq!(std::fs::read(blob.data_path(&registry(&storage))).unwrap(), b"hello");
+    let req = test::TestRequest::get().uri(&location).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    // Empty blobs
+    let location = start_upload(&app, "foo").await;
+    let req = test::TestRequest::put()
+        .uri(&format!(
+            "{}?digest=sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            location
+        ))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers().get("content-range").unwrap(), "0-0");
+}
+
+#[actix_web::test]
+async fn full_push_pull_cycle() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(ServerConfig {
+        gc_grace_period_secs: Some(0),
+        ..open_config(&storage)
+    });
+    let root = registry(&storage);
+
+    let layer = "layer content".repeat(100);
+    let layer_digest = format!("sha256:{}", sha256sum_str(&layer).unwrap());
+    let config_digest = format!("sha256:{}", sha256sum_str("{}").unwrap());
+
+    // Push the layer in chunks
+    let location = start_upload(&app, "library/app").await;
+    let (first, second) = layer.split_at(500);
+    for chunk in [first, second] {
+        let req = test::TestRequest::patch()
+            .uri(&location)
+            .set_payload(chunk.to_string())
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+    }
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest={}", location, layer_digest))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    // Push the config in a single request
+    let location = start_upload(&app, "library/app").await;
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest={}", location, config_digest))
+        .set_payload("{}")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    // Push the manifest
+    let manifest = serde_json::json!({
+        "schemaVersion": 2,
+        "mediaType": DOCKER_MANIFEST_V2,
+        "config": {"mediaType": "application/vnd.docker.container.image.v1+json", "digest": config_digest, "size": 2},
+        "layers": [{
+            "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+            "digest": layer_digest,
+            "size": layer.len(),
+        }],
+    })
+    .to_string();
+    let manifest_digest = format!("sha256:{}", sha256sum_str(&manifest).unwrap());
+    let req = test::TestRequest::put()
+        .uri("/v2/library/app/manifests/1.0")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(manifest.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(
+        res.headers().get("docker-content-digest").unwrap().to_str().unwrap(),
+        manifest_digest
+    );
+
+    // Pull
+    let req = test::TestRequest::get().uri("/v2/_catalog").to_request();
+    let catalog: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(catalog["repositories"], serde_json::json!(["library/app"]));
+
+    let req = test::TestRequest::get().uri("/v2/library/app/tags/list").to_request();
+    let tags: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(tags["tags"], serde_json::json!(["1.0"]));
+
+    let req = test::TestRequest::get()
+        .uri("/v2/library/app/manifests/1.0")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("content-type").unwrap(), DOCKER_MANIFEST_V2);
+    assert_eq!(test::read_body(res).await, manifest);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/library/app/blobs/{}", layer_digest))
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, layer);
+
+    // Delete the manifest and its tag, the garbage collector removes the orphaned blobs
+    let req = test::TestRequest::delete()
+        .uri(&format!("/v2/library/app/manifests/{}", manifest_digest))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+
+    let req = test::TestRequest::get()
+        .uri("/v2/library/app/manifests/1.0")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+    for digest in [&layer_digest, &config_digest, &manifest_digest] {
+        assert!(!BlobReference::from_str(digest).unwrap().data_path(&root).exists());
+    }
+}
+
+#[actix_web::test]
+async fn realm_honors_trusted_forwarded_headers() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let forwarded_request = |peer: &str| {
+        test::TestRequest::get()
+            .uri("/v2/")
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-Forwarded-Proto", "https"))
+            .insert_header(("X-Forwarded-Host", "registry.example.com"))
+            .to_request()
+    };
+
+    let app = init_app!(test_config(&storage));
+    let res = test::call_service(&app, forwarded_request("10.0.0.1:1234")).await;
+    let auth = res.headers().get("www-authenticate").unwrap().to_str().unwrap();
+    assert!(auth.contains("realm=\"http://localhost/token\""), "{}", auth);
+
+    let conf = ServerConfig {
+        trust_forwarded_headers: true,
+        trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        ..test_config(&storage)
+    };
+    let app = init_app!(conf);
+    let res = test::call_service(&app, forwarded_request("10.0.0.1:1234")).await;
+    let auth = res.headers().get("www-authenticate").unwrap().to_str().unwrap();
+    assert!(
+        auth.contains("realm=\"https://registry.example.com/token\""),
+        "{}",
+        auth
+    );
+    assert!(auth.contains("service=\"localhost\""), "{}", auth);
+
+    // Clients can not forge the headers
+    let res = test::call_service(&app, forwarded_request("192.168.1.1:1234")).await;
+    let auth = res.headers().get("www-authenticate").unwrap().to_str().unwrap();
+    assert!(auth.contains("realm=\"http://localhost/token\""), "{}", auth);
+}
+
+#[actix_web::test]
+async fn token_service_is_the_host_of_the_access_url() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+
+    for (access_url, token_service, expected) in [
+        (
+            "https://registry.example.com:5000/registry",
+            None,
+            "registry.example.com:5000",
+        ),
+        ("https://registry.example.com/", None, "registry.example.com"),
+        (
+            "https://registry.example.com/registry",
+            Some("auth.example.com"),
+            "auth.example.com",
+        ),
+    ] {
+        let conf = ServerConfig {
+            access_url: access_url.to_string(),
+            token_service: token_service.map(str::to_string),
+            ..test_config(&storage)
+        };
+        let app = init_app!(conf.clone());
+
+        let req = test::TestRequest::get().uri("/v2/").to_request();
+        let res = test::call_service(&app, req).await;
+        let auth = res.headers().get("www-authenticate").unwrap().to_str().unwrap();
+        assert!(auth.contains(&format!("service=\"{}\"", expected)), "{}", auth);
+
+        // Tokens are issued for the same service
+        let req = test::TestRequest::get()
+            .uri(&format!("/token?service={}", expected))
+            .insert_header(basic_auth(TEST_USER, TEST_PASSWORD))
+            .to_request();
+        let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::get()
+            .uri("/v2/")
+            .insert_header(bearer_auth(&res.access_token))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+}
+
+#[actix_web::test]
+async fn client_ip_is_only_forwarded_by_trusted_proxies() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        ..open_config(&storage)
+    };
+    let request = |peer: &str, header: (&str, &str)| {
+        test::TestRequest::get()
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(header)
+            .to_http_request()
+    };
+
+    // Trusted proxy
+    let req = request("10.0.0.1:1234", ("X-Forwarded-For", "203.0.113.7, 10.0.0.2"));
+    assert_eq!(conf.client_ip(&req), Some("203.0.113.7".parse().unwrap()));
+    let req = request("10.0.0.1:1234", ("Forwarded", "for=\"[2001:db8::1]:4711\";proto=https"));
+    assert_eq!(conf.client_ip(&req), Some("2001:db8::1".parse().unwrap()));
+
+    // A client can not forge its address through a trusted proxy
+    let req = request("10.0.0.1:1234", ("X-Forwarded-For", "1.2.3.4, 203.0.113.7"));
+    assert_eq!(conf.client_ip(&req), Some("203.0.113.7".parse().unwrap()));
+
+    // Untrusted peer
+    let req = request("198.51.100.3:1234", ("X-Forwarded-For", "203.0.113.7"));
+    assert_eq!(conf.client_ip(&req), Some("198.51.100.3".parse().unwrap()));
+}
+
+#[actix_web::test]
+async fn large_manifests_are_streamed_to_disk() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let scratch = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(ServerConfig {
+        max_body_size: Some(4 * 1024 * 1024),
+        temp_dir: Some(scratch.to_path_buf()),
+        ..open_config(&storage)
+    });
+
+    let mut manifest: serde_json::Value = serde_json::from_str(TEST_MANIFEST).unwrap();
+    let layer = serde_json::json!({
+        "mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip",
+        "size": 5,
+        "digest": format!("sha256:{}", HELLO_SHA256),
+        "annotations": {"description": "a".repeat(1000)},
+    });
+    manifest["layers"] = serde_json::Value::Array(vec![layer; 900]);
+    let manifest = manifest.to_string();
+    assert!(manifest.len() > 900_000);
+
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(manifest.clone())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+
+    let digest = format!("sha256:{}", sha256sum_str(&manifest).unwrap());
+    assert_eq!(
+        res.headers().get("docker-content-digest").unwrap().to_str().unwrap(),
+        digest
+    );
+    let stored = BlobReference::from_str(&digest).unwrap().data_path(&registry(&storage));
+    assert_eq!(std::fs::read_to_string(stored).unwrap(), manifest);
+
+    // No temporary file is left behind
+    assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+}
+
+#[actix_web::test]
+async fn manifest_revisions_include_untagged_ones() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    push_test_manifest(&app, "foo", "latest").await;
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+        .set_payload(TEST_OCI_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let req = test::TestRequest::get().uri("/v2/foo/_manifests").to_request();
+    let res: crate::api::DockerManifestRevisions = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.name, "foo");
+    assert_eq!(res.revisions.len(), 2);
+
+    let old = format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap());
+    let new = format!("sha256:{}", sha256sum_str(TEST_OCI_MANIFEST).unwrap());
+    for revision in res.revisions {
+        if revision.digest == old {
+            assert!(revision.tags.is_empty());
+        } else {
+            assert_eq!(revision.digest, new);
+            assert_eq!(revision.tags, vec!["latest"]);
+        }
+    }
+
+    let req = test::TestRequest::get().uri("/v2/bar/_manifests").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn blobs_are_served_with_their_declared_media_type() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let config_digest = format!("sha256:{}", sha256sum_str("{}").unwrap());
+    for (digest, content) in [
+        (config_digest.clone(), "{}"),
+        (format!("sha256:{}", HELLO_SHA256), "hello"),
+    ] {
+        let location = start_upload(&app, "foo").await;
+        let req = test::TestRequest::put()
+            .uri(&format!("{}?digest={}", location, digest))
+            .set_payload(content)
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    }
+
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+        .set_payload(TEST_OCI_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/{}", config_digest))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/vnd.oci.image.config.v1+json"
+    );
+
+    // Broken revisions of the image are skipped
+    let image = DockerImage::new(&registry(&storage), "foo");
+    let broken = crate::storage::store_blob(&registry(&storage), "not a manifest".as_bytes()).unwrap();
+    crate::storage::link_manifest(&image, None, &broken, false).unwrap();
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/{}", config_digest))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.headers().get("content-type").unwrap(),
+        "application/vnd.oci.image.config.v1+json"
+    );
+
+    // Blobs no manifest references fall back to a generic content type
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/blobs/sha256:{}", HELLO_SHA256))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("content-type").unwrap(), "application/octet-stream");
+}
+
+#[actix_web::test]
+async fn periodic_gc_reclaims_orphaned_blobs() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        gc_interval_secs: std::num::NonZeroU64::new(1),
+        ..open_config(&storage)
+    };
+    let state = Data::new(crate::state::ServerState::default());
+    let gc_task = spawn_periodic_gc(conf.clone(), state.clone()).unwrap();
+
+    // Orphans created after startup, the recent one possibly belonging to a push in progress
+    let orphan = BlobReference::from_sha256sum(sha256sum_str("orphan").unwrap());
+    write_file_atomic(&orphan.data_path(&registry(&storage)), "orphan", false).unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(orphan.data_path(&registry(&storage)))
+        .unwrap()
+        .set_modified(std::time::SystemTime::now() - conf.gc_grace_period() * 2)
+        .unwrap();
+    let pushed = BlobReference::from_sha256sum(sha256sum_str("pushed").unwrap());
+    write_file_atomic(&pushed.data_path(&registry(&storage)), "pushed", false).unwrap();
+
+    for _ in 0..50 {
+        if state.gc_status().last_run.is_some() {
+            break;
+        }
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    gc_task.abort();
+
+    assert!(state.gc_status().last_run.is_some());
+    assert!(!orphan.data_path(&registry(&storage)).exists());
+    assert!(pushed.data_path(&registry(&storage)).exists());
+
+    // Without interval, no task is spawned
+    assert!(spawn_periodic_gc(open_config(&storage), state).is_none());
+}
+
+#[actix_web::test]
+async fn deleting_manifests_requires_a_valid_digest() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    for (reference, code) in [
+        ("bogus", "DIGEST_INVALID"),
+        ("sha256:abc", "DIGEST_INVALID"),
+        ("latest", "UNSUPPORTED"),
+    ] {
+        let req = test::TestRequest::delete()
+            .uri(&format!("/v2/foo/manifests/{}", reference))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], code);
+    }
+
+    let req = test::TestRequest::get().uri("/v2/foo/manifests/latest").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn pushes_are_limited_by_the_repository_quota() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        repo_quota_bytes: Some(TEST_MANIFEST.len() as u64 + 10),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    // The blobs referenced by the manifests of the repository, and the ones pushed to it but
+    // not referenced yet, count towards its quota
+    push_test_manifest(&app, "foo", "latest").await;
+
+    for (content, status) in [
+        ("hello", StatusCode::CREATED),
+        ("01234", StatusCode::CREATED),
+        ("a", StatusCode::PAYLOAD_TOO_LARGE),
+    ] {
+        let location = start_upload(&app, "foo").await;
+        let req = test::TestRequest::put()
+            .uri(&format!(
+                "{}?digest=sha256:{}",
+                location,
+                sha256sum_str(content).unwrap()
+            ))
+            .set_payload(content)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), status, "{}", content);
+
+        if status == StatusCode::PAYLOAD_TOO_LARGE {
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["errors"][0]["code"], "DENIED");
+
+            // The upload is kept, to be finalized again once space is freed
+            let req = test::TestRequest::get().uri(&location).to_request();
+            assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+        }
+    }
+
+    // Other repositories have their own quota
+    push_test_manifest(&app, "bar", "latest").await;
+}
+
+#[actix_web::test]
+async fn manifests_count_their_new_layers_in_the_quota() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let layer = "layer".repeat(16);
+    let layer_digest = format!("sha256:{}", sha256sum_str(&layer).unwrap());
+    let manifest = TEST_MANIFEST.replace(
+        "\"layers\": []",
+        &format!(
+            r#""layers": [{{"mediaType": "application/vnd.docker.image.rootfs.diff.tar.gzip", "digest": "{}", "size": {}}}]"#,
+            layer_digest,
+            layer.len()
+        ),
+    );
+    let app = init_app!(ServerConfig {
+        repo_quota_bytes: Some(manifest.len() as u64 + 50),
+        ..open_config(&storage)
+    });
+
+    // The layer fits in the quota of the repository it is pushed to
+    let location = start_upload(&app, "bar").await;
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest={}", location, layer_digest))
+        .set_payload(layer.clone())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+
+    // But referencing it from another repository does not fit in its quota
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", DOCKER_MANIFEST_V2))
+        .set_payload(manifest)
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::PAYLOAD_TOO_LARGE
+    );
+    assert!(!DockerImage::new(&registry(&storage), "foo").image_path().exists());
+}
+
+#[actix_web::test]
+async fn extended_catalog_reports_last_updates() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    // Pretend the repository was pushed long ago
+    let image = DockerImage::new(&registry(&storage), "foo");
+    let past = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+    for link in [
+        image.manifest_tag_link_path("latest"),
+        image.manifest_revision_path(&image.resolve_manifest("latest").unwrap()),
+    ] {
+        std::fs::File::options()
+            .write(true)
+            .open(link)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+    }
+
+    let last_updated = |app| async move {
+        let req = test::TestRequest::get().uri("/v2/_catalog?extended=true").to_request();
+        let catalog: serde_json::Value = test::call_and_read_body_json(app, req).await;
+        assert_eq!(catalog["repositories"][0]["name"], "foo");
+        catalog["repositories"][0]["last_updated"].as_u64().unwrap()
+    };
+    assert_eq!(last_updated(&app).await, 1000);
+
+    let before = time();
+    push_test_manifest(&app, "foo", "other").await;
+    assert!(last_updated(&app).await >= before);
+
+    // The default catalog stays spec compliant
+    let req = test::TestRequest::get().uri("/v2/_catalog").to_request();
+    let catalog: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(catalog, serde_json::json!({ "repositories": ["foo"] }));
+}
+
+#[actix_web::test]
+async fn referrers_can_be_filtered_by_artifact_type() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/manifests/latest")
+        .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+        .set_payload(TEST_OCI_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    let subject = format!("sha256:{}", sha256sum_str(TEST_OCI_MANIFEST).unwrap());
+
+    for artifact_type in [
+        "application/vnd.example.sbom.v1",
+        "application/vnd.example.signature.v1",
+    ] {
+        let manifest = serde_json::json!({
+            "schemaVersion": 2,
+            "mediaType": OCI_IMAGE_MANIFEST_V1,
+            "artifactType": artifact_type,
+            "config": {
+                "mediaType": "application/vnd.oci.empty.v1+json",
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "size": 2
+            },
+            "layers": [],
+            "subject": { "mediaType": OCI_IMAGE_MANIFEST_V1, "digest": subject, "size": TEST_OCI_MANIFEST.len() }
+        })
+        .to_string();
+        let digest = format!("sha256:{}", sha256sum_str(&manifest).unwrap());
+
+        let req = test::TestRequest::put()
+            .uri(&format!("/v2/foo/manifests/{}", digest))
+            .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+            .set_payload(manifest)
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers().get("oci-subject").unwrap().to_str().unwrap(), subject);
+    }
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/v2/foo/referrers/{}?artifactType=application/vnd.example.sbom.v1",
+            subject
+        ))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers().get("content-type").unwrap(), OCI_IMAGE_INDEX_V1);
+    assert_eq!(res.headers().get("oci-filters-applied").unwrap(), "artifactType");
+    assert_eq!(res.headers().get("oci-subject").unwrap().to_str().unwrap(), subject);
+    let index: crate::api::DockerReferrersIndex = test::read_body_json(res).await;
+    assert_eq!(index.mediaType, OCI_IMAGE_INDEX_V1);
+    assert_eq!(index.manifests.len(), 1);
+    assert_eq!(
+        index.manifests[0].artifactType.as_deref(),
+        Some("application/vnd.example.sbom.v1")
+    );
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/referrers/{}", subject))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert!(res.headers().get("oci-filters-applied").is_none());
+    let index: crate::api::DockerReferrersIndex = test::read_body_json(res).await;
+    assert_eq!(index.manifests.len(), 2);
+}
+
+#[actix_web::test]
+async fn version_is_reported_without_authentication() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(test_config(&storage));
+
+    let req = test::TestRequest::get().uri("/version").to_request();
+    let res: crate::api::VersionInfo = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.name, "dockerust");
+    assert_eq!(res.version, env!("CARGO_PKG_VERSION"));
+}
+
+#[actix_web::test]
+async fn tags_can_be_resolved_to_digests() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    let req = test::TestRequest::get().uri("/v2/foo/tags/latest/digest").to_request();
+    let res: crate::api::DockerTagDigest = test::call_and_read_body_json(&app, req).await;
+    assert_eq!(res.digest, format!("sha256:{}", sha256sum_str(TEST_MANIFEST).unwrap()));
+
+    let req = test::TestRequest::get().uri("/v2/foo/tags/missing/digest").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["errors"][0]["code"], "MANIFEST_UNKNOWN");
+
+    // Invalid tags never reach the storage
+    for tag in ["..", "-latest"] {
+        let req = test::TestRequest::get()
+            .uri(&format!("/v2/foo/tags/{}/digest", tag))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", tag);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "TAG_INVALID");
+    }
+}
+
+#[actix_web::test]
+async fn unknown_uploads_are_reported() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    // Upload started for another image
+    let location = start_upload(&app, "foo").await;
+    let uuid = location.rsplit('/').next().unwrap();
+
+    for uri in [
+        "/v2/foo/blobs/uploads/00000000-0000-0000-0000-000000000000".to_string(),
+        format!("/v2/bar/blobs/uploads/{}", uuid),
+    ] {
+        for method in [Method::GET, Method::PATCH, Method::PUT, Method::DELETE] {
+            let req = test::TestRequest::default()
+                .method(method.clone())
+                .uri(&format!("{}?digest=sha256:{}", uri, HELLO_SHA256))
+                .set_payload("hello")
+                .to_request();
+            let res = test::call_service(&app, req).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND, "{} {}", method, uri);
+            let body: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(body["errors"][0]["code"], "BLOB_UPLOAD_UNKNOWN");
+        }
+    }
+
+    // The upload is still usable from its own image
+    let req = test::TestRequest::get().uri(&location).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NO_CONTENT);
+}
+
+#[actix_web::test]
+async fn vanished_uploads_are_unknown() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let location = start_upload(&app, "foo").await;
+    let uuid = location.rsplit('/').next().unwrap();
+    let image = DockerImage::new(&registry(&storage), "foo");
+
+    // Upload cancelled behind the back of the client, e.g. by another client
+    std::fs::remove_file(image.upload_storage_path(uuid)).unwrap();
+
+    for req in [
+        test::TestRequest::get().uri(&location),
+        test::TestRequest::patch().uri(&location).set_payload("hello"),
+        test::TestRequest::put()
+            .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+            .set_payload("hello"),
+        test::TestRequest::delete().uri(&location),
+    ] {
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(body["errors"][0]["code"], "BLOB_UPLOAD_UNKNOWN");
+    }
+}
+
+#[actix_web::test]
+async fn uploads_can_be_stored_apart_from_blobs() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let uploads = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        upload_path: Some(uploads.to_path_buf()),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    let location = start_upload(&app, "foo/bar").await;
+    let uuid = location.rsplit('/').next().unwrap();
+    assert!(uploads.join("foo/bar").join(uuid).exists());
+    assert!(!DockerImage::new(&registry(&storage), "foo/bar")
+        .upload_storage_path(uuid)
+        .exists());
+
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+        .set_payload("hello")
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    assert!(!uploads.join("foo/bar").join(uuid).exists());
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/v2/foo/bar/blobs/sha256:{}", HELLO_SHA256))
+        .to_request();
+    assert_eq!(test::call_and_read_body(&app, req).await, "hello");
+}
+
+#[actix_web::test]
+async fn manifests_are_buffered_in_the_configured_temp_dir() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let scratch = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        temp_dir: Some(scratch.join("manifests")),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    push_test_manifest(&app, "foo", "latest").await;
+    assert!(scratch.join("manifests").is_dir());
+    assert_eq!(std::fs::read_dir(scratch.join("manifests")).unwrap().count(), 0);
+    assert!(!registry(&storage).join("_uploads").exists());
+
+    // Scratch files go to the temporary directory of the system by default
+    assert_eq!(open_config(&storage).temp_path(), std::env::temp_dir());
+}
+
+#[actix_web::test]
+async fn identical_manifests_are_not_rewritten() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let scratch = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(ServerConfig {
+        temp_dir: Some(scratch.to_path_buf()),
+        ..open_config(&storage)
+    });
+    push_test_manifest(&app, "foo", "latest").await;
+
+    let blob = BlobReference::from_sha256sum(sha256sum_str(TEST_MANIFEST).unwrap());
+    let blob_path = blob.data_path(&registry(&storage));
+    let past = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+    std::fs::File::options()
+        .write(true)
+        .open(&blob_path)
+        .unwrap()
+        .set_modified(past)
+        .unwrap();
+
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(&blob_path.metadata().unwrap());
+
+    // The stored manifest is kept, only marked as pushed again for the garbage collector
+    push_test_manifest(&app, "foo", "other").await;
+    assert!(blob_path.metadata().unwrap().modified().unwrap() > past);
+    #[cfg(unix)]
+    assert_eq!(
+        std::os::unix::fs::MetadataExt::ino(&blob_path.metadata().unwrap()),
+        inode
+    );
+
+    let image = DockerImage::new(&registry(&storage), "foo");
+    assert_eq!(image.resolve_manifest("other").unwrap(), blob);
+    assert!(std::fs::read_dir(&scratch).unwrap().next().is_none());
+}
+
+#[actix_web::test]
+async fn repositories_can_be_deleted() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let conf = ServerConfig {
+        enable_delete: true,
+        gc_grace_period_secs: Some(0),
+        ..open_config(&storage)
+    };
+    let app = init_app!(conf);
+
+    push_test_manifest(&app, "foo/bar", "latest").await;
+    let req = test::TestRequest::put()
+        .uri("/v2/foo/bar/manifests/oci")
+        .insert_header(("Content-Type", OCI_IMAGE_MANIFEST_V1))
+        .set_payload(TEST_OCI_MANIFEST)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::CREATED);
+    push_test_manifest(&app, "other", "latest").await;
+
+    let oci_manifest = BlobReference::from_sha256sum(sha256sum_str(TEST_OCI_MANIFEST).unwrap());
+    let req = test::TestRequest::delete().uri("/v2/foo/bar/").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+
+    assert!(!registry(&storage).join("repositories/foo").exists());
+    assert!(!oci_manifest.data_path(&registry(&storage)).exists());
+
+    // Blobs still referenced by other repositories are kept
+    let req = test::TestRequest::get().uri("/v2/other/manifests/latest").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = test::TestRequest::delete().uri("/v2/foo/bar/").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn repository_deletion_requires_an_admin() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    push_test_manifest(&init_app!(open_config(&storage)), "foo", "latest").await;
+
+    let mut conf = test_config(&storage);
+    conf.credentials.push(Credentials {
+        user_name: "admin".to_string(),
+        password_hash: bcrypt::hash(TEST_PASSWORD, 4).unwrap(),
+        admin: true,
+    });
+    let conf = ServerConfig {
+        enable_delete: true,
+        max_concurrent_uploads: Some(1),
+        ..conf
+    };
+    let app = init_app!(conf);
+
+    let mut tokens = vec![];
+    for user in [TEST_USER, "admin"] {
+        let req = test::TestRequest::get()
+            .uri("/token?scope=repository:foo:*")
+            .insert_header(basic_auth(user, TEST_PASSWORD))
+            .to_request();
+        let res: AuthResponse = test::call_and_read_body_json(&app, req).await;
+        tokens.push(res.access_token);
+    }
+
+    let req = test::TestRequest::delete()
+        .uri("/v2/foo/")
+        .insert_header(bearer_auth(&tokens[0]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::UNAUTHORIZED);
+    assert!(DockerImage::new(&registry(&storage), "foo").image_path().exists());
+
+    // Uploads in progress end with the repository, releasing their slot
+    let req = test::TestRequest::post()
+        .uri("/v2/foo/blobs/uploads/")
+        .insert_header(bearer_auth(&tokens[1]))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let location = res.headers().get("location").unwrap().to_str().unwrap().to_string();
+
+    let req = test::TestRequest::delete()
+        .uri("/v2/foo/")
+        .insert_header(bearer_auth(&tokens[1]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+    assert!(!DockerImage::new(&registry(&storage), "foo").image_path().exists());
+
+    let req = test::TestRequest::get()
+        .uri(&location)
+        .insert_header(bearer_auth(&tokens[1]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::NOT_FOUND);
+
+    let req = test::TestRequest::post()
+        .uri("/v2/foo/blobs/uploads/")
+        .insert_header(bearer_auth(&tokens[1]))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::ACCEPTED);
+}
+
+#[actix_web::test]
+async fn repository_deletion_requires_enabling_deletes() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+    push_test_manifest(&app, "foo", "latest").await;
+
+    let req = test::TestRequest::delete().uri("/v2/foo/").to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert!(DockerImage::new(&registry(&storage), "foo").image_path().exists());
+}
+
+#[actix_web::test]
+async fn upload_responses_identify_the_upload() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let app = init_app!(open_config(&storage));
+
+    let req = test::TestRequest::post().uri("/v2/foo/blobs/uploads/").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::ACCEPTED);
+    let uuid = res
+        .headers()
+        .get(UPLOAD_UUID_HEADER)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    let location = format!("/v2/foo/blobs/uploads/{}", uuid);
+
+    let req = test::TestRequest::patch()
+        .uri(&location)
+        .set_payload("hello")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get(UPLOAD_UUID_HEADER).unwrap().to_str().unwrap(), uuid);
+
+    let req = test::TestRequest::get().uri(&location).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get(UPLOAD_UUID_HEADER).unwrap().to_str().unwrap(), uuid);
+
+    // Finalized and cancelled uploads are identified too, even when the request fails
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, "0".repeat(64)))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(res.headers().get(UPLOAD_UUID_HEADER).unwrap().to_str().unwrap(), uuid);
+
+    let location = start_upload(&app, "foo").await;
+    let uuid = location.rsplit('/').next().unwrap();
+    let req = test::TestRequest::put()
+        .uri(&format!("{}?digest=sha256:{}", location, HELLO_SHA256))
+        .set_payload("hello")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.headers().get(UPLOAD_UUID_HEADER).unwrap().to_str().unwrap(), uuid);
+
+    let location = start_upload(&app, "foo").await;
+    let uuid = location.rsplit('/').next().unwrap();
+    let req = test::TestRequest::delete().uri(&location).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    assert_eq!(res.headers().get(UPLOAD_UUID_HEADER).unwrap().to_str().unwrap(), uuid);
+}
+
+/// Certificate and private key, PEM encoded
+struct TestCert {
+    cert: String,
+    key: String,
+}
+
+/// Issue a certificate for `name`, signed by `ca` or self-signed
+fn issue_cert(name: &str, ca: Option<&rcgen::Certificate>) -> (rcgen::Certificate, TestCert) {
+    let mut params = rcgen::CertificateParams::new(vec![name.to_string()]);
+    params.distinguished_name.push(rcgen::DnType::CommonName, name);
+    if ca.is_none() {
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    }
+
+    let cert = rcgen::Certificate::from_params(params).unwrap();
+    let pem = TestCert {
+        cert: match ca {
+            Some(ca) => cert.serialize_pem_with_signer(ca).unwrap(),
+            None => cert.serialize_pem().unwrap(),
+        },
+        key: cert.serialize_private_key_pem(),
+    };
+    (cert, pem)
+}
+
+/// Send a raw HTTP/1.1 request over TLS and return the status code of the response, if any
+fn tls_request(addr: SocketAddr, ca: &str, client: Option<&TestCert>, method: &str, uri: &str) -> Option<u16> {
+    use std::io::Read;
+
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut ca.as_bytes()).unwrap() {
+        roots.add(&rustls::Certificate(cert)).unwrap();
+    }
+
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+    let config = match client {
+        None => builder.with_no_client_auth(),
+        Some(client) => {
+            let certs = rustls_pemfile::certs(&mut client.cert.as_bytes()).unwrap();
+            let mut keys = rustls_pemfile::pkcs8_private_keys(&mut client.key.as_bytes()).unwrap();
+            builder
+                .with_client_auth_cert(
+                    certs.into_iter().map(rustls::Certificate).collect(),
+                    rustls::PrivateKey(keys.remove(0)),
+                )
+                .unwrap()
+        }
+    };
+
+    let conn = rustls::ClientConnection::new(std::sync::Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+    let mut stream = rustls::StreamOwned::new(conn, std::net::TcpStream::connect(addr).unwrap());
+    let request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        method, uri
+    );
+    stream.write_all(request.as_bytes()).ok()?;
+
+    // The server may close the connection without notifying the end of the TLS session
+    let mut response = vec![];
+    let _ = stream.read_to_end(&mut response);
+    String::from_utf8_lossy(&response).split(' ').nth(1)?.parse().ok()
+}
+
+#[actix_web::test]
+async fn client_certificates_are_required_to_push() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let (ca, ca_pem) = issue_cert("Test CA", None);
+    let (_, server_pem) = issue_cert("localhost", Some(&ca));
+    let (_, client_pem) = issue_cert("pusher", Some(&ca));
+    let (_, rogue_pem) = issue_cert("rogue", None);
+
+    let write = |name: &str, content: &str| {
+        let path = storage.as_path().join(name);
+        std::fs::write(&path, content).unwrap();
+        Some(path)
+    };
+    let config = ServerConfig {
+        tls_cert_path: write("server.pem", &server_pem.cert),
+        tls_key_path: write("server.key", &server_pem.key),
+        client_ca_path: write("ca.pem", &ca_pem.cert),
+        workers: NonZeroUsize::new(1),
+        ..open_config(&storage)
+    };
+    config.check_tls().unwrap();
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = run_server(config, Data::default(), listener).unwrap();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let statuses = actix_web::rt::task::spawn_blocking(move || {
+        let request = |client, method, uri| tls_request(addr, &ca_pem.cert, client, method, uri);
+        [
+            request(None, "GET", "/v2/"),
+            request(None, "POST", "/v2/foo/blobs/uploads/"),
+            request(Some(&client_pem), "POST", "/v2/foo/blobs/uploads/"),
+            // Certificates issued by another authority fail the handshake
+            request(Some(&rogue_pem), "POST", "/v2/foo/blobs/uploads/"),
+        ]
+    })
+    .await
+    .unwrap();
+    assert_eq!(statuses, [Some(200), Some(401), Some(202), None]);
+
+    handle.stop(false).await;
+}
+
+#[actix_web::test]
+async fn client_ca_requires_tls() {
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let config = ServerConfig {
+        client_ca_path: Some(storage.as_path().join("ca.pem")),
+        ..open_config(&storage)
+    };
+    assert!(config.check_tls().is_err());
+    assert!(open_config(&storage).check_tls().is_ok());
+}
+
+#[actix_web::test]
+async fn stalled_transfers_are_dropped() {
+    use std::io::Read;
+
+    let storage = mktemp::Temp::new_dir().unwrap();
+    let content = vec![b'a'; 16 * 1024 * 1024];
+    let blob = crate::storage::store_blob(&registry(&storage), content.as_slice()).unwrap();
+
+    let config = ServerConfig {
+        stream_idle_timeout_secs: NonZeroU64::new(1),
+        workers: NonZeroUsize::new(1),
+        ..open_config(&storage)
+    };
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = run_server(config, Data::default(), listener).unwrap();
+    let handle = server.handle();
+    actix_web::rt::spawn(server);
+
+    let received = actix_web::rt::task::spawn_blocking(move || {
+        // A small receive buffer makes the client stall the transfer quickly
+        let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        socket.set_recv_buffer_size(4096).unwrap();
+        socket.connect(&addr.into()).unwrap();
+        let mut stream: std::net::TcpStream = socket.into();
+
+        write!(
+            stream,
+            "GET /v2/foo/blobs/{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            blob.to_digest()
+        )
+        .unwrap();
+
+        // Stop reading for longer than the idle timeout
+        std::thread::sleep(Duration::from_secs(5));
+
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut received = vec![];
+        let _ = stream.read_to_end(&mut received);
+        received.len()
+    })
+    .await
+    .unwrap();
+    assert!(received < content.len(), "the whole blob was received");
+
+    handle.stop(false).await;
+}
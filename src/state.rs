@@ -0,0 +1,214 @@
+//! State shared between the workers of the server
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::RegistryResult;
+use crate::log_error;
+use crate::storage::clean_storage;
+use crate::utils::time;
+
+/// An upload in progress
+struct ActiveUpload {
+    path: PathBuf,
+    user: String,
+    /// Last time a request was made on the upload
+    last_activity: Instant,
+    /// Number of requests on the upload in progress
+    requests: usize,
+}
+
+/// A blob pushed to an image
+struct PushedBlob {
+    size: u64,
+    time: Instant,
+}
+
+/// Outcome of the last garbage collection
+#[derive(Clone, Copy, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct GcStatus {
+    /// Completion time of the last garbage collection, as a UNIX timestamp
+    pub last_run: Option<u64>,
+    /// Duration of the last garbage collection, in milliseconds
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct ServerState {
+    /// Uploads in progress, indexed by UUID
+    uploads: Mutex<HashMap<String, ActiveUpload>>,
+    /// Last garbage collection
+    gc: Mutex<GcStatus>,
+    /// Held exclusively while blobs are deleted, by the garbage collector or on request, and
+    /// shared while manifests start referencing blobs
+    gc_lock: RwLock<()>,
+    /// Blobs pushed to an image that may not be referenced by its manifests yet, indexed by
+    /// image name then digest
+    pushed_blobs: Mutex<HashMap<String, HashMap<String, PushedBlob>>>,
+}
+
+impl ServerState {
+    /// Run the garbage collector on the registry, and record its completion. See
+    /// [`clean_storage`] for `grace`
+    pub fn run_gc(&self, storage: &Path, grace: Duration) -> RegistryResult<()> {
+        let _guard = self.lock_blob_deletion();
+
+        let start = Instant::now();
+        clean_storage(storage, grace)?;
+
+        *self.gc.lock().unwrap_or_else(PoisonError::into_inner) = GcStatus {
+            last_run: Some(time()),
+            duration_ms: Some(start.elapsed().as_millis() as u64),
+        };
+        Ok(())
+    }
+
+    /// Keep blobs from being deleted while the guard is held, e.g. while a manifest is pushed
+    pub fn lock_blob_references(&self) -> RwLockReadGuard<'_, ()> {
+        self.gc_lock.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Keep manifests from being pushed while blobs are deleted
+    pub fn lock_blob_deletion(&self) -> RwLockWriteGuard<'_, ()> {
+        self.gc_lock.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Get the outcome of the last garbage collection
+    pub fn gc_status(&self) -> GcStatus {
+        *self.gc.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register a new upload, unless the global or the per-user limit of concurrent uploads
+    /// is reached
+    pub fn try_start_upload(
+        &self,
+        uuid: &str,
+        path: PathBuf,
+        user: &str,
+        max_uploads: Option<usize>,
+        max_user_uploads: Option<usize>,
+    ) -> bool {
+        let mut uploads = self.uploads.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Forget the uploads that ended without being reported, as their file is gone
+        uploads.retain(|_, u| u.path.exists());
+
+        let user_uploads = uploads.values().filter(|u| u.user == user).count();
+        if max_uploads.is_some_and(|m| uploads.len() >= m) || max_user_uploads.is_some_and(|m| user_uploads >= m) {
+            return false;
+        }
+
+        uploads.insert(
+            uuid.to_string(),
+            ActiveUpload {
+                path,
+                user: user.to_string(),
+                last_activity: Instant::now(),
+                requests: 0,
+            },
+        );
+        true
+    }
+
+    /// Record the start of a request on an upload. The upload does not expire until the
+    /// returned guard is dropped, however long the request streams its body
+    pub fn start_upload_request(&self, uuid: &str) -> UploadRequest<'_> {
+        self.update_upload(uuid, |u| u.requests += 1);
+        UploadRequest {
+            state: self,
+            uuid: uuid.to_string(),
+        }
+    }
+
+    fn update_upload(&self, uuid: &str, f: impl FnOnce(&mut ActiveUpload)) {
+        if let Some(upload) = self
+            .uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_mut(uuid)
+        {
+            upload.last_activity = Instant::now();
+            f(upload);
+        }
+    }
+
+    /// Cancel the uploads left idle for longer than `max_idle`, removing their file, so that
+    /// abandoned uploads do not hold their slot. Returns the number of cancelled uploads
+    pub fn expire_uploads(&self, max_idle: Duration) -> usize {
+        let mut uploads = self.uploads.lock().unwrap_or_else(PoisonError::into_inner);
+        let before = uploads.len();
+        uploads.retain(|_, u| {
+            if u.requests > 0 || u.last_activity.elapsed() <= max_idle {
+                return true;
+            }
+
+            if let Err(e) = std::fs::remove_file(&u.path) {
+                log_error!("Failed to remove expired upload {}: {}", u.path.display(), e);
+            }
+            false
+        });
+
+        before - uploads.len()
+    }
+
+    /// Record a blob pushed to an image, to count it in the quota of the image until a manifest
+    /// references it
+    pub fn add_pushed_blob(&self, image: &str, digest: String, size: u64) {
+        self.pushed_blobs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(image.to_string())
+            .or_default()
+            .insert(
+                digest,
+                PushedBlob {
+                    size,
+                    time: Instant::now(),
+                },
+            );
+    }
+
+    /// Get the digests and sizes of the blobs pushed to an image less than `max_age` ago,
+    /// forgetting the older ones
+    pub fn pushed_blobs(&self, image: &str, max_age: Duration) -> Vec<(String, u64)> {
+        let mut pushed = self.pushed_blobs.lock().unwrap_or_else(PoisonError::into_inner);
+        let Some(blobs) = pushed.get_mut(image) else {
+            return vec![];
+        };
+
+        blobs.retain(|_, b| b.time.elapsed() <= max_age);
+        let res = blobs.iter().map(|(digest, b)| (digest.clone(), b.size)).collect();
+        if blobs.is_empty() {
+            pushed.remove(image);
+        }
+        res
+    }
+
+    /// Report the end of an upload
+    pub fn end_upload(&self, uuid: &str) {
+        self.uploads.lock().unwrap_or_else(PoisonError::into_inner).remove(uuid);
+    }
+
+    /// Report the end of the uploads stored in `dir`, e.g. when their repository is deleted
+    pub fn end_uploads_in(&self, dir: &Path) {
+        self.uploads
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, u| u.path.parent() != Some(dir));
+    }
+}
+
+/// Request in progress on an upload, see [`ServerState::start_upload_request`]
+pub struct UploadRequest<'a> {
+    state: &'a ServerState,
+    uuid: String,
+}
+
+impl Drop for UploadRequest<'_> {
+    fn drop(&mut self) {
+        self.state
+            .update_upload(&self.uuid, |u| u.requests = u.requests.saturating_sub(1));
+    }
+}
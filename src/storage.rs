@@ -1,10 +1,32 @@
-use std::io::ErrorKind;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{DirEntry, FileType, OpenOptions};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use regex::Regex;
+use sha2::{Digest, Sha256};
+
+use crate::constants::{GC_LOCK_FILE, MAX_IMAGE_NAME_DEPTH, MAX_IMAGE_NAME_LENGTH};
 use crate::docker::{DockerBlobRef, DockerManifest, DockerManifestOrManifestList};
+use crate::error::{RegistryError, RegistryResult};
+use crate::utils::{create_empty_file, create_file_atomic, fsync, move_file, sha256sum, sha512sum, write_file_atomic};
+use crate::{log_error, log_info};
+
+/// Write locks of the images, indexed by image path
+static IMAGE_LOCKS: Mutex<BTreeMap<PathBuf, Arc<Mutex<()>>>> = Mutex::new(BTreeMap::new());
+
+/// Grammar of the algorithm of a digest, compiled once as digests are parsed on every request
+static DIGEST_ALGORITHM: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-z0-9]+(?:[+._-][a-z0-9]+)*$").unwrap());
+
+/// Grammar of a component of a repository name
+static NAME_COMPONENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^[a-z0-9]+(?:(?:[._]|__|-+)[a-z0-9]+)*$").unwrap());
 
-const BASE_PATH: &str = "docker/registry/v2/";
+/// Grammar of a tag
+static TAG_NAME: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}$").unwrap());
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct BlobReference {
@@ -13,7 +35,7 @@ pub struct BlobReference {
 }
 
 impl BlobReference {
-    pub fn from_docker_blob_ref(r: &DockerBlobRef) -> std::io::Result<Self> {
+    pub fn from_docker_blob_ref(r: &DockerBlobRef) -> RegistryResult<Self> {
         Self::from_str(&r.digest)
     }
 
@@ -28,8 +50,19 @@ impl BlobReference {
         Self::from_str(r).is_ok()
     }
 
-    pub fn from_file(path: &Path) -> std::io::Result<Self> {
-        Self::from_str(&std::fs::read_to_string(path)?)
+    /// Read a reference stored in a link file. An invalid content means the storage is corrupted
+    pub fn from_file(path: &Path) -> RegistryResult<Self> {
+        Self::from_str(&std::fs::read_to_string(path)?).map_err(|e| {
+            RegistryError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            ))
+        })
+    }
+
+    /// Get the hashing algorithm of the blob
+    pub fn alg(&self) -> &str {
+        &self.alg
     }
 
     pub fn to_digest(&self) -> String {
@@ -38,7 +71,6 @@ impl BlobReference {
 
     pub fn data_path(&self, storage_path: &Path) -> PathBuf {
         storage_path
-            .join(BASE_PATH)
             .join("blobs")
             .join(&self.alg)
             .join(&self.hash[..2])
@@ -46,23 +78,46 @@ impl BlobReference {
             .join("data")
     }
 
+    /// Get the path of the data of the blob, checking it actually exists
+    pub fn existing_data_path(&self, storage_path: &Path) -> RegistryResult<PathBuf> {
+        let path = self.data_path(storage_path);
+
+        if !path.exists() {
+            return Err(RegistryError::BlobMissing(self.to_digest()));
+        }
+
+        Ok(path)
+    }
+
     pub fn is_empty_ref(&self) -> bool {
         self.alg == "sha256" && self.hash == "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
     }
 }
 
 impl FromStr for BlobReference {
-    type Err = std::io::Error;
+    type Err = RegistryError;
 
     fn from_str(content: &str) -> Result<Self, Self::Err> {
         let split = content.splitn(2, ':').collect::<Vec<_>>();
 
         if split.len() != 2 {
-            return Err(std::io::Error::new(ErrorKind::Other, "Expected 2 entries!"));
+            return Err(RegistryError::InvalidDigest(content.to_string()));
         }
 
-        if split[1].len() <= 2 {
-            return Err(std::io::Error::new(ErrorKind::Other, "Blob hash is too small!"));
+        // Digests are case sensitive, only lowercase hex hashes are accepted
+        let expected_len = match split[0] {
+            "sha256" => Some(64),
+            "sha512" => Some(128),
+            _ => None,
+        };
+        if !DIGEST_ALGORITHM.is_match(split[0])
+            || split[1].len() <= 2
+            || expected_len.is_some_and(|l| split[1].len() != l)
+            || !split[1]
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        {
+            return Err(RegistryError::InvalidDigest(content.to_string()));
         }
 
         Ok(Self {
@@ -72,10 +127,13 @@ impl FromStr for BlobReference {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DockerImage {
+    /// Root of the registry storage, see [`crate::server::ServerConfig::registry_path`]
     pub storage_path: PathBuf,
     pub image: String,
+    /// Root of the uploads, when they are not stored with the image
+    pub upload_path: Option<PathBuf>,
 }
 
 impl DockerImage {
@@ -83,11 +141,18 @@ impl DockerImage {
         Self {
             storage_path: storage.to_path_buf(),
             image: image.to_string(),
+            upload_path: None,
         }
     }
 
+    /// Store the uploads of the image under another root than the registry storage
+    pub fn with_upload_path(mut self, upload_path: Option<&Path>) -> Self {
+        self.upload_path = upload_path.map(Path::to_path_buf);
+        self
+    }
+
     pub fn image_path(&self) -> PathBuf {
-        self.storage_path.join(BASE_PATH).join("repositories").join(&self.image)
+        self.storage_path.join("repositories").join(&self.image)
     }
 
     pub fn tags_path(&self) -> PathBuf {
@@ -98,7 +163,9 @@ impl DockerImage {
         self.image_path().join("_manifests/revisions")
     }
 
-    pub fn tags_list(&self) -> std::io::Result<Vec<String>> {
+    /// Get the list of tags of the image, sorted in byte order so that paginating over it
+    /// with `last` is consistent across calls
+    pub fn tags_list(&self) -> RegistryResult<Vec<String>> {
         let mut list = vec![];
         if !self.tags_path().exists() {
             return Ok(vec![]);
@@ -115,10 +182,11 @@ impl DockerImage {
                 }
             }
         }
+        list.sort();
         Ok(list)
     }
 
-    pub fn get_tags_attached_to_manifest_blob(&self, b: &BlobReference) -> std::io::Result<Vec<String>> {
+    pub fn get_tags_attached_to_manifest_blob(&self, b: &BlobReference) -> RegistryResult<Vec<String>> {
         let mut list = vec![];
 
         for tag in self.tags_list()? {
@@ -132,7 +200,7 @@ impl DockerImage {
         Ok(list)
     }
 
-    pub fn manifests_revision_list(&self) -> std::io::Result<Vec<BlobReference>> {
+    pub fn manifests_revision_list(&self) -> RegistryResult<Vec<BlobReference>> {
         let list_path = self.revisions_path().join("sha256");
         if !list_path.exists() {
             return Ok(vec![]);
@@ -151,6 +219,104 @@ impl DockerImage {
         Ok(list)
     }
 
+    /// Get the manifests of this image that reference a blob, directly or through an index
+    pub fn manifests_referencing_blob(&self, blob: &BlobReference) -> RegistryResult<Vec<BlobReference>> {
+        let mut list = vec![];
+        for manifest_ref in self.manifests_revision_list()? {
+            if !is_blob_useless_in_distribution_file(blob, &manifest_ref, &self.storage_path)? {
+                list.push(manifest_ref);
+            }
+        }
+        list.sort_by_key(BlobReference::to_digest);
+        Ok(list)
+    }
+
+    /// Get the time of the last push to the image, from the modification time of its tag and
+    /// revision links, as a UNIX timestamp
+    pub fn last_updated(&self) -> RegistryResult<Option<u64>> {
+        let mut links = self
+            .tags_list()?
+            .iter()
+            .map(|t| self.manifest_tag_link_path(t))
+            .collect::<Vec<_>>();
+        for manifest in self.manifests_revision_list()? {
+            links.push(self.manifest_revision_path(&manifest));
+        }
+
+        let mut last_updated = None;
+        for link in links {
+            let modified = link.metadata()?.modified()?;
+            let secs = modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            last_updated = last_updated.max(Some(secs));
+        }
+        Ok(last_updated)
+    }
+
+    /// Get the digests of the manifests of the image and of the blobs they reference
+    pub fn referenced_blobs(&self) -> RegistryResult<BTreeSet<String>> {
+        let mut blobs = BTreeSet::new();
+        for manifest in self.manifests_revision_list()? {
+            referenced_blobs(&manifest, &self.storage_path, &mut blobs)?;
+        }
+        Ok(blobs)
+    }
+
+    /// Get the total size of the blobs referenced by the image
+    pub fn referenced_size(&self) -> RegistryResult<u64> {
+        let mut size = 0;
+        for digest in self.referenced_blobs()? {
+            if let Ok(metadata) = BlobReference::from_str(&digest)?
+                .data_path(&self.storage_path)
+                .metadata()
+            {
+                size += metadata.len();
+            }
+        }
+        Ok(size)
+    }
+
+    /// Get the media type declared for a blob by the image manifests referencing it, if any
+    pub fn blob_media_type(&self, blob: &BlobReference) -> RegistryResult<Option<String>> {
+        for manifest_ref in self.manifests_revision_list()? {
+            let manifest_path = manifest_ref.data_path(&self.storage_path);
+            if !manifest_path.exists() {
+                continue;
+            }
+
+            // A broken manifest must not prevent serving the blobs of the others
+            let Ok(manifest) =
+                serde_json::from_str::<DockerManifestOrManifestList>(&std::fs::read_to_string(manifest_path)?)
+            else {
+                continue;
+            };
+
+            if let Some(manifest) = manifest.get_manifest() {
+                let declared = std::iter::once(&manifest.config)
+                    .chain(&manifest.layers)
+                    .find(|b| BlobReference::from_docker_blob_ref(b).is_ok_and(|b| &b == blob));
+                if let Some(declared) = declared {
+                    return Ok(Some(declared.mediaType.clone()));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Get the manifest blob designated by a tag or a digest
+    pub fn resolve_manifest(&self, reference: &str) -> RegistryResult<BlobReference> {
+        if BlobReference::is_valid_reference(reference) {
+            return BlobReference::from_str(reference);
+        }
+
+        let link_path = self.manifest_tag_link_path(reference);
+        if !link_path.exists() {
+            return Err(RegistryError::ManifestUnknown(reference.to_string()));
+        }
+
+        BlobReference::from_file(&link_path)
+    }
+
     pub fn manifest_tag_link_path(&self, manifest_ref: &str) -> PathBuf {
         self.tags_path().join(manifest_ref).join("current/link")
     }
@@ -159,21 +325,83 @@ impl DockerImage {
         self.revisions_path().join(&blob.alg).join(&blob.hash).join("link")
     }
 
+    /// Get the directory holding the uploads in progress of this image
+    pub fn uploads_path(&self) -> PathBuf {
+        match &self.upload_path {
+            Some(root) => root.join(&self.image),
+            None => self.image_path().join("_uploads"),
+        }
+    }
+
     pub fn upload_storage_path(&self, uuid: &str) -> PathBuf {
-        self.image_path().join("_uploads").join(uuid)
+        self.uploads_path().join(uuid)
+    }
+
+    /// Get the in-process lock serializing the changes made to the manifests of this image
+    pub fn write_lock(&self) -> Arc<Mutex<()>> {
+        let mut locks = IMAGE_LOCKS.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Forget the locks that are not held anymore
+        locks.retain(|_, l| Arc::strong_count(l) > 1);
+
+        locks.entry(self.image_path()).or_default().clone()
+    }
+}
+
+/// Check an image name against the grammar and the limits of the distribution spec
+pub fn check_image_name(name: &str) -> Result<(), &'static str> {
+    if name.len() > MAX_IMAGE_NAME_LENGTH {
+        return Err("repository name is too long");
+    }
+
+    if name.split('/').count() > MAX_IMAGE_NAME_DEPTH {
+        return Err("repository name has too many components");
     }
+
+    if !name.split('/').all(|c| NAME_COMPONENT.is_match(c)) {
+        return Err("invalid repository name");
+    }
+
+    Ok(())
 }
 
-pub fn recurse_images_scan(path: &Path, start: &Path) -> std::io::Result<Vec<String>> {
+/// Get the entries of a directory along with their file type
+///
+/// Entries that can not be inspected are logged and skipped, so that a single odd file does
+/// not abort a whole scan of the storage
+fn scan_dir(path: &Path) -> std::io::Result<Vec<(DirEntry, FileType)>> {
+    let mut list = vec![];
+
+    for entry in std::fs::read_dir(path)? {
+        match entry.and_then(|e| e.file_type().map(|t| (e, t))) {
+            Ok(entry) => list.push(entry),
+            Err(e) => log_error!("Skipping unreadable entry of {}: {}", path.display(), e),
+        }
+    }
+
+    Ok(list)
+}
+
+/// Scan the sub-directory of a storage tree, logging and skipping it if it can not be read
+fn scan_sub_dir<T: Default>(path: &Path, scan: impl FnOnce(&Path) -> RegistryResult<T>) -> RegistryResult<T> {
+    match scan(path) {
+        Err(RegistryError::Io(e)) => {
+            log_error!("Skipping unreadable directory {}: {}", path.display(), e);
+            Ok(T::default())
+        }
+        res => res,
+    }
+}
+
+pub fn recurse_images_scan(path: &Path, start: &Path) -> RegistryResult<Vec<String>> {
     if !path.exists() || !path.is_dir() {
         return Ok(vec![]);
     }
 
     let mut list = vec![];
 
-    for entry in std::fs::read_dir(path)? {
-        let entry = entry?;
-        if !entry.file_type()?.is_dir() {
+    for (entry, file_type) in scan_dir(path)? {
+        if !file_type.is_dir() {
             continue;
         }
 
@@ -183,46 +411,50 @@ pub fn recurse_images_scan(path: &Path, start: &Path) -> std::io::Result<Vec<Str
 
             return Ok(vec![image_path[start_path.len() + 1..].to_string()]);
         } else {
-            list.append(&mut recurse_images_scan(&entry.path(), start)?);
+            list.append(&mut scan_sub_dir(&entry.path(), |p| recurse_images_scan(p, start))?);
         }
     }
 
     Ok(list)
 }
 
-/// Get the entire list of docker image available
-pub fn get_docker_images_list(storage: &Path) -> std::io::Result<Vec<String>> {
-    let start = storage.join(BASE_PATH).join("repositories");
+/// Get the entire list of docker image available, sorted in byte order
+pub fn get_docker_images_list(storage: &Path) -> RegistryResult<Vec<String>> {
+    let start = storage.join("repositories");
     let mut list = recurse_images_scan(&start, &start)?;
     list.sort();
     Ok(list)
 }
 
-/// Get the entire list of blob references
-pub fn get_blob_list(storage: &Path) -> std::io::Result<Vec<BlobReference>> {
-    let root = storage.join(BASE_PATH).join("blobs/sha256");
+/// Get the entire list of blob references, whatever the algorithm of their digest
+pub fn get_blob_list(storage: &Path) -> RegistryResult<Vec<BlobReference>> {
     let mut list = vec![];
 
-    if !root.exists() {
-        return Ok(list);
-    }
-
-    // First level parsing
-    for entry in std::fs::read_dir(root)? {
-        let entry = entry?;
+    for alg in ["sha256", "sha512"] {
+        let root = storage.join("blobs").join(alg);
 
-        if !entry.metadata()?.is_dir() {
+        if !root.exists() {
             continue;
         }
 
-        // Second level parsing
-        for entry in std::fs::read_dir(entry.path())? {
-            let entry = entry?;
+        // First level parsing
+        for (entry, file_type) in scan_dir(&root)? {
+            if !file_type.is_dir() {
+                continue;
+            }
 
-            if entry.metadata()?.is_dir() {
-                list.push(BlobReference::from_sha256sum(
-                    entry.file_name().to_string_lossy().to_string(),
-                ))
+            // Second level parsing
+            let sub_dirs = scan_sub_dir(&entry.path(), |p| Ok(scan_dir(p)?))?;
+            for (entry, file_type) in sub_dirs {
+                if !file_type.is_dir() {
+                    continue;
+                }
+
+                let digest = format!("{}:{}", alg, entry.file_name().to_string_lossy());
+                match BlobReference::from_str(&digest) {
+                    Ok(blob_ref) => list.push(blob_ref),
+                    Err(e) => log_error!("Skipping {}: {}", entry.path().display(), e),
+                }
             }
         }
     }
@@ -230,14 +462,14 @@ pub fn get_blob_list(storage: &Path) -> std::io::Result<Vec<BlobReference>> {
     Ok(list)
 }
 
-fn is_blob_useless_in_docker_manifest(blob_ref: &BlobReference, manifest: &DockerManifest) -> std::io::Result<bool> {
+fn is_blob_useless_in_docker_manifest(blob_ref: &BlobReference, manifest: &DockerManifest) -> RegistryResult<bool> {
     // Check config
     if &BlobReference::from_docker_blob_ref(&manifest.config)? == blob_ref {
         return Ok(false);
     }
 
-    // Check layers
-    for layer in &manifest.layers {
+    // Check layers, except the foreign ones that are not stored locally
+    for layer in manifest.layers.iter().filter(|l| !l.is_foreign()) {
         if &BlobReference::from_docker_blob_ref(layer)? == blob_ref {
             return Ok(false);
         }
@@ -251,7 +483,7 @@ fn is_blob_useless_in_distribution_file(
     blob_ref: &BlobReference,
     upper_manifest_ref: &BlobReference,
     storage: &Path,
-) -> std::io::Result<bool> {
+) -> RegistryResult<bool> {
     let manifest_path = upper_manifest_ref.data_path(storage);
 
     if !manifest_path.exists() {
@@ -284,14 +516,54 @@ fn is_blob_useless_in_distribution_file(
             }
         }
     } else {
-        eprintln!("Unknown manifest type! {}", manifest.mediaType);
+        log_error!("Unknown manifest type! {}", manifest.media_type());
     }
 
     Ok(true)
 }
 
+/// Collect the digests of the blobs referenced by a manifest, including the manifest itself
+pub fn referenced_blobs(
+    manifest_ref: &BlobReference,
+    storage: &Path,
+    blobs: &mut BTreeSet<String>,
+) -> RegistryResult<()> {
+    // Do not visit a manifest twice
+    if !blobs.insert(manifest_ref.to_digest()) {
+        return Ok(());
+    }
+
+    let manifest_path = manifest_ref.data_path(storage);
+    if !manifest_path.exists() {
+        return Ok(());
+    }
+
+    let manifest: DockerManifestOrManifestList = serde_json::from_str(&std::fs::read_to_string(manifest_path)?)?;
+    manifest_referenced_blobs(&manifest, storage, blobs)
+}
+
+/// Collect the digests of the blobs referenced by a parsed manifest, including the ones of the
+/// manifests of a list, but not the manifest itself
+pub fn manifest_referenced_blobs(
+    manifest: &DockerManifestOrManifestList,
+    storage: &Path,
+    blobs: &mut BTreeSet<String>,
+) -> RegistryResult<()> {
+    if let Some(manifest) = manifest.get_manifest() {
+        for blob in std::iter::once(&manifest.config).chain(manifest.layers.iter().filter(|l| !l.is_foreign())) {
+            blobs.insert(BlobReference::from_docker_blob_ref(blob)?.to_digest());
+        }
+    } else if let Some(manifests_list) = manifest.get_manifests_list() {
+        for child in &manifests_list.manifests {
+            referenced_blobs(&BlobReference::from_docker_blob_ref(child)?, storage, blobs)?;
+        }
+    }
+
+    Ok(())
+}
+
 /// Check if a blob is useless or not
-pub fn is_blob_useless(blob_ref: &BlobReference, storage: &Path) -> std::io::Result<bool> {
+pub fn is_blob_useless(blob_ref: &BlobReference, storage: &Path) -> RegistryResult<bool> {
     // Scan all images
     for image in get_docker_images_list(storage)? {
         let image = DockerImage::new(storage, &image);
@@ -322,16 +594,14 @@ pub fn is_blob_useless(blob_ref: &BlobReference, storage: &Path) -> std::io::Res
 }
 
 /// Remove empty directories
-fn remove_empty_dirs(path: &Path, can_remove: bool) -> std::io::Result<()> {
+fn remove_empty_dirs(path: &Path, can_remove: bool) -> RegistryResult<()> {
     let mut found_files = false;
 
-    for entry in std::fs::read_dir(path)? {
-        let entry = entry?;
-
+    for (entry, file_type) in scan_dir(path)? {
         found_files = true;
 
-        if entry.metadata()?.is_dir() {
-            remove_empty_dirs(&entry.path(), true)?;
+        if file_type.is_dir() {
+            scan_sub_dir(&entry.path(), |p| remove_empty_dirs(p, true))?;
         }
     }
 
@@ -342,8 +612,136 @@ fn remove_empty_dirs(path: &Path, can_remove: bool) -> std::io::Result<()> {
     Ok(())
 }
 
-/// Run the garbage collector
-pub fn clean_storage(storage: &Path) -> std::io::Result<()> {
+/// Remove the files of a directory and of its sub-directories older than a given age
+fn remove_old_files(dir: &Path, max_age: Duration) -> RegistryResult<usize> {
+    let mut removed = 0;
+    for (entry, file_type) in scan_dir(dir)? {
+        if file_type.is_dir() {
+            removed += scan_sub_dir(&entry.path(), |p| remove_old_files(p, max_age))?;
+            continue;
+        }
+
+        if !file_type.is_file() {
+            continue;
+        }
+
+        let age = entry.metadata()?.modified()?.elapsed().unwrap_or_default();
+        if age > max_age {
+            std::fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Remove the uploads and the temporary manifests older than a given age, left behind by a
+/// previous run of the registry. Returns the number of removed files
+pub fn clean_stale_uploads(storage: &Path, upload_path: Option<&Path>, max_age: Duration) -> RegistryResult<usize> {
+    let mut dirs = vec![storage.join("_uploads")];
+    for image in get_docker_images_list(storage)? {
+        dirs.push(DockerImage::new(storage, &image).image_path().join("_uploads"));
+    }
+    dirs.extend(upload_path.map(Path::to_path_buf));
+
+    let mut removed = 0;
+    for dir in dirs.iter().filter(|d| d.is_dir()) {
+        removed += remove_old_files(dir, max_age)?;
+    }
+
+    Ok(removed)
+}
+
+/// Check whether a blob was stored or pushed again less than `grace` ago
+fn is_blob_recent(blob: &BlobReference, storage: &Path, grace: Duration) -> bool {
+    std::fs::metadata(blob.data_path(storage))
+        .and_then(|m| m.modified())
+        .is_ok_and(|t| t.elapsed().map_or(true, |age| age < grace))
+}
+
+/// Check if a process is still running
+fn is_process_alive(pid: u32) -> bool {
+    // Collections never overlap within a process, so a lock bearing its PID was left by a
+    // previous process that had the same PID, as happens in containers
+    if pid == std::process::id() {
+        return false;
+    }
+
+    #[cfg(target_os = "linux")]
+    return Path::new("/proc").join(pid.to_string()).exists();
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    return std::process::Command::new("kill")
+        .args(["-0", &pid.to_string()])
+        .stderr(std::process::Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success());
+
+    // The owner can not be checked, the lock has to be removed by hand
+    #[cfg(not(unix))]
+    true
+}
+
+/// Lock file held while the garbage collector runs, so that the processes sharing a storage
+/// never collect it at the same time. The file holds the PID of its owner
+struct GcLock {
+    path: PathBuf,
+}
+
+impl GcLock {
+    /// Take the lock of a registry, unless a running process holds it. The locks left by the
+    /// processes that died while collecting are removed
+    fn acquire(storage: &Path) -> RegistryResult<Option<Self>> {
+        let path = storage.join(GC_LOCK_FILE);
+
+        for _ in 0..2 {
+            // The PID is written along with the file, so others never find a lock without owner
+            match create_file_atomic(&path, std::process::id().to_string()) {
+                Ok(()) => return Ok(Some(Self { path })),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e.into()),
+            }
+
+            let owner = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|pid| pid.trim().parse().ok());
+            if owner.is_some_and(is_process_alive) {
+                return Ok(None);
+            }
+
+            log_info!("Removing stale garbage collector lock {}", path.display());
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl Drop for GcLock {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log_error!("Failed to remove garbage collector lock {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Run the garbage collector, unless another process is running it on the same storage
+///
+/// The blobs stored less than `grace` ago are kept, as the manifest referencing them may not
+/// have been pushed yet
+pub fn clean_storage(storage: &Path, grace: Duration) -> RegistryResult<()> {
+    if !storage.is_dir() {
+        return Ok(());
+    }
+
+    let Some(_lock) = GcLock::acquire(storage)? else {
+        log_info!("Skipping garbage collection, another process is running it");
+        return Ok(());
+    };
+
     for _ in 0..3 {
         for blob in get_blob_list(storage)? {
             // Empty blob
@@ -351,11 +749,15 @@ pub fn clean_storage(storage: &Path) -> std::io::Result<()> {
                 continue;
             }
 
+            if is_blob_recent(&blob, storage, grace) {
+                continue;
+            }
+
             if !is_blob_useless(&blob, storage)? {
                 continue;
             }
 
-            println!("Deleting useless blob {}", blob.to_digest());
+            log_info!("Deleting useless blob {}", blob.to_digest());
             std::fs::remove_dir_all(blob.data_path(storage).parent().unwrap())?;
         }
 
@@ -364,3 +766,507 @@ pub fn clean_storage(storage: &Path) -> std::io::Result<()> {
 
     Ok(())
 }
+
+/// Check a tag name against the grammar of the distribution spec
+pub fn check_tag_name(tag: &str) -> Result<(), &'static str> {
+    if !TAG_NAME.is_match(tag) {
+        return Err("invalid tag name");
+    }
+
+    Ok(())
+}
+
+/// Check that the stored content of a blob still matches its digest. Blobs using an unknown
+/// algorithm are assumed to be intact
+pub fn is_blob_intact(storage: &Path, blob_ref: &BlobReference) -> RegistryResult<bool> {
+    let path = blob_ref.data_path(storage);
+    let hash = match blob_ref.alg() {
+        "sha256" => sha256sum(&path)?,
+        "sha512" => sha512sum(&path)?,
+        _ => return Ok(true),
+    };
+
+    Ok(hash == blob_ref.hash)
+}
+
+/// Move a file holding the data of a blob to its place in the storage, unless the same blob
+/// is already stored intact. The file is consumed either way
+pub fn commit_blob(storage: &Path, file: &Path, blob_ref: &BlobReference, durable: bool) -> RegistryResult<()> {
+    let blob_path = blob_ref.data_path(storage);
+    let same_len = blob_path
+        .metadata()
+        .is_ok_and(|m| m.len() == std::fs::metadata(file).map(|f| f.len()).unwrap_or(u64::MAX));
+    if same_len && is_blob_intact(storage, blob_ref)? {
+        std::fs::remove_file(file)?;
+
+        // The blob is pushed again, so the garbage collector keeps it for a whole grace period
+        OpenOptions::new()
+            .write(true)
+            .open(&blob_path)?
+            .set_modified(SystemTime::now())?;
+        return Ok(());
+    }
+
+    if durable {
+        fsync(file)?;
+    }
+    create_empty_file(&blob_path)?;
+    move_file(file, &blob_path, durable)?;
+    if durable {
+        fsync(blob_path.parent().unwrap_or(&blob_path))?;
+    }
+
+    Ok(())
+}
+
+/// Make a manifest blob a revision of an image, and point a tag to it if any
+pub fn link_manifest(
+    image: &DockerImage,
+    tag: Option<&str>,
+    blob_ref: &BlobReference,
+    durable: bool,
+) -> RegistryResult<()> {
+    let mut links = vec![image.manifest_revision_path(blob_ref)];
+    links.extend(tag.map(|t| image.manifest_tag_link_path(t)));
+
+    for link in links {
+        write_file_atomic(&link, blob_ref.to_digest(), durable)?;
+    }
+
+    Ok(())
+}
+
+/// Store a blob read from `reader`, and get its reference
+///
+/// The data is written to the `_uploads` directory of the storage while it is hashed, then
+/// moved in place
+pub fn store_blob(storage: &Path, mut reader: impl Read) -> RegistryResult<BlobReference> {
+    let temp_dir = storage.join("_uploads");
+    std::fs::create_dir_all(&temp_dir)?;
+    let temp = mktemp::Temp::new_file_in(&temp_dir)?;
+
+    let mut file = std::fs::File::create(&temp)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+        file.write_all(&buf[..len])?;
+    }
+    drop(file);
+
+    let blob_ref = BlobReference::from_sha256sum(format!("{:x}", hasher.finalize()));
+    commit_blob(storage, &temp, &blob_ref, true)?;
+    temp.release();
+
+    Ok(blob_ref)
+}
+
+/// Store a manifest and tag it in an image, and get its reference
+///
+/// The blobs the manifest references are expected to be stored already. The size index, if
+/// any, is not updated: rebuild it with the `rebuild-index` command
+pub fn store_manifest(storage: &Path, image: &str, tag: &str, manifest: &[u8]) -> RegistryResult<BlobReference> {
+    check_image_name(image).map_err(|e| RegistryError::NameInvalid(format!("{}: {}", image, e)))?;
+    check_tag_name(tag).map_err(|e| RegistryError::TagInvalid(format!("{}: {}", tag, e)))?;
+
+    match serde_json::from_slice::<DockerManifestOrManifestList>(manifest) {
+        Ok(m) if m.schemaVersion == 2 => {}
+        Ok(m) => {
+            return Err(RegistryError::ManifestInvalid(format!(
+                "unsupported schema version {}",
+                m.schemaVersion
+            )))
+        }
+        Err(e) => return Err(RegistryError::ManifestInvalid(e.to_string())),
+    }
+
+    let image = DockerImage::new(storage, image);
+    let lock = image.write_lock();
+    let _guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+
+    let blob_ref = store_blob(storage, manifest)?;
+    link_manifest(&image, Some(tag), &blob_ref, true)?;
+
+    Ok(blob_ref)
+}
+
+/// Get the tags of an image, sorted
+pub fn list_tags(storage: &Path, image: &str) -> RegistryResult<Vec<String>> {
+    DockerImage::new(storage, image).tags_list()
+}
+
+/// Get the manifest a tag of an image points to
+pub fn resolve_tag(storage: &Path, image: &str, tag: &str) -> RegistryResult<BlobReference> {
+    check_tag_name(tag).map_err(|e| RegistryError::TagInvalid(format!("{}: {}", tag, e)))?;
+    DockerImage::new(storage, image).resolve_manifest(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_digests_are_invalid() {
+        for digest in ["nope", "sha256:ab"] {
+            assert!(matches!(
+                BlobReference::from_str(digest),
+                Err(RegistryError::InvalidDigest(d)) if d == digest
+            ));
+        }
+    }
+
+    #[test]
+    fn digests_must_be_lowercase_hex() {
+        let hash = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        assert!(BlobReference::from_str(&format!("sha256:{}", hash)).is_ok());
+
+        for digest in [
+            format!("sha256:{}", hash.to_uppercase()),
+            format!("SHA256:{}", hash),
+            format!("sha256:{}", hash.replace('e', "g")),
+            format!("sha256:{}", &hash[1..]),
+            format!("sha512:{}", hash),
+            format!("../sha256:{}", hash),
+        ] {
+            assert!(
+                matches!(BlobReference::from_str(&digest), Err(RegistryError::InvalidDigest(_))),
+                "{}",
+                digest
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_manifests_and_blobs() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let image = DockerImage::new(&storage, "foo");
+
+        assert!(matches!(
+            image.resolve_manifest("latest"),
+            Err(RegistryError::ManifestUnknown(r)) if r == "latest"
+        ));
+
+        let blob = BlobReference::from_sha256sum(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+        );
+        assert!(matches!(
+            blob.existing_data_path(&storage),
+            Err(RegistryError::BlobMissing(d)) if d == blob.to_digest()
+        ));
+    }
+
+    #[test]
+    fn corrupted_and_missing_links() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let image = DockerImage::new(&storage, "foo");
+        crate::utils::write_file_atomic(&image.manifest_tag_link_path("latest"), "corrupted", false).unwrap();
+
+        assert!(matches!(image.resolve_manifest("latest"), Err(RegistryError::Io(_))));
+        assert!(matches!(
+            BlobReference::from_file(&storage.join("missing")),
+            Err(RegistryError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn images_and_tags_are_listed_in_byte_order() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+
+        for name in ["b", "a/z", "a0", "a-b"] {
+            std::fs::create_dir_all(storage.join("repositories").join(name).join("_manifests")).unwrap();
+        }
+        assert_eq!(get_docker_images_list(&storage).unwrap(), vec!["a-b", "a/z", "a0", "b"]);
+
+        let image = DockerImage::new(&storage, "b");
+        for tag in ["v2", "latest", "V1", "1.0"] {
+            crate::utils::create_empty_file(&image.manifest_tag_link_path(tag)).unwrap();
+        }
+        assert_eq!(image.tags_list().unwrap(), vec!["1.0", "V1", "latest", "v2"]);
+    }
+
+    fn put_blob(storage: &Path, content: &str) -> BlobReference {
+        store_blob(storage, content.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn stale_uploads_are_removed() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let image = DockerImage::new(&storage, "foo");
+        std::fs::create_dir_all(image.revisions_path()).unwrap();
+
+        let uploads = mktemp::Temp::new_dir().unwrap();
+        let old = [
+            image.upload_storage_path("old"),
+            storage.join("_uploads/manifest"),
+            image
+                .clone()
+                .with_upload_path(Some(&uploads))
+                .upload_storage_path("old"),
+        ];
+        let fresh = image.upload_storage_path("fresh");
+        for path in old.iter().chain([&fresh]) {
+            crate::utils::create_empty_file(path).unwrap();
+        }
+        for path in &old {
+            let past = std::time::SystemTime::now() - Duration::from_secs(3600);
+            std::fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(past)
+                .unwrap();
+        }
+
+        assert_eq!(
+            clean_stale_uploads(&storage, Some(&uploads), Duration::from_secs(60)).unwrap(),
+            3
+        );
+        assert!(old.iter().all(|p| !p.exists()));
+        assert!(fresh.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn scans_skip_odd_entries() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let blob = put_blob(&storage, "layer");
+        std::fs::create_dir_all(storage.join("repositories/good/_manifests")).unwrap();
+
+        for root in [storage.join("repositories"), storage.join("blobs/sha256")] {
+            std::os::unix::fs::symlink(storage.join("missing"), root.join("dangling")).unwrap();
+            std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap();
+
+            let locked = root.join("locked");
+            std::fs::create_dir(&locked).unwrap();
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000)).unwrap();
+
+            // Permissions do not apply to root, which can read the directory anyway
+            if std::fs::read_dir(&locked).is_ok() {
+                eprintln!("Skipping scans_skip_odd_entries, directories can not be made unreadable");
+                return;
+            }
+        }
+
+        assert_eq!(get_docker_images_list(&storage).unwrap(), vec!["good"]);
+        assert_eq!(get_blob_list(&storage).unwrap(), vec![blob]);
+        clean_storage(&storage, Duration::ZERO).unwrap();
+    }
+
+    #[test]
+    fn gc_follows_oci_indexes() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+
+        let config = put_blob(&storage, "{}");
+        let layer = put_blob(&storage, "layer");
+        let orphan = put_blob(&storage, "orphan");
+        let manifest = put_blob(
+            &storage,
+            &format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json",
+                "config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":2}},
+                "layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar","digest":"{}","size":5}}]}}"#,
+                config.to_digest(),
+                layer.to_digest()
+            ),
+        );
+        let index = put_blob(
+            &storage,
+            &format!(
+                r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json",
+                "manifests":[{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{}"}}]}}"#,
+                manifest.to_digest()
+            ),
+        );
+
+        // Only the index is referenced by the image
+        let image = DockerImage::new(&storage, "foo");
+        for link in [
+            image.manifest_revision_path(&index),
+            image.manifest_tag_link_path("latest"),
+        ] {
+            crate::utils::write_file_atomic(&link, index.to_digest(), false).unwrap();
+        }
+
+        clean_storage(&storage, Duration::ZERO).unwrap();
+
+        for blob in [&config, &layer, &manifest, &index] {
+            assert!(blob.data_path(&storage).exists(), "{} was deleted", blob.to_digest());
+        }
+        assert!(!orphan.data_path(&storage).exists());
+    }
+
+    #[test]
+    fn gc_collects_blobs_of_every_algorithm() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let sha256 = put_blob(&storage, "orphan");
+        let sha512 = BlobReference::from_str(&format!("sha512:{:x}", sha2::Sha512::digest("orphan"))).unwrap();
+        crate::utils::write_file_atomic(&sha512.data_path(&storage), "orphan", false).unwrap();
+
+        assert_eq!(get_blob_list(&storage).unwrap(), vec![sha256, sha512]);
+
+        clean_storage(&storage, Duration::ZERO).unwrap();
+        assert!(get_blob_list(&storage).unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn gc_locks_of_dead_processes_are_removed() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let orphan = put_blob(&storage, "orphan");
+
+        // Held by a running process
+        let parent = std::os::unix::process::parent_id();
+        std::fs::write(storage.join(GC_LOCK_FILE), parent.to_string()).unwrap();
+        clean_storage(&storage, Duration::ZERO).unwrap();
+        assert!(orphan.data_path(&storage).exists());
+
+        // Left by a process that crashed
+        std::fs::write(storage.join(GC_LOCK_FILE), "4000000000").unwrap();
+        clean_storage(&storage, Duration::ZERO).unwrap();
+        assert!(!orphan.data_path(&storage).exists());
+        assert!(!storage.join(GC_LOCK_FILE).exists());
+    }
+
+    #[test]
+    fn blobs_are_stored_through_the_library() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+
+        let blob = store_blob(&storage, "hello".as_bytes()).unwrap();
+        assert_eq!(
+            blob.to_digest(),
+            "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(std::fs::read_to_string(blob.data_path(&storage)).unwrap(), "hello");
+
+        // Storing the same blob again keeps the stored one, leaving no scratch file behind
+        let path = blob.data_path(&storage);
+        assert_eq!(store_blob(&storage, "hello".as_bytes()).unwrap(), blob);
+        assert_eq!(get_blob_list(&storage).unwrap(), vec![blob]);
+        assert_eq!(std::fs::read_dir(storage.join("_uploads")).unwrap().count(), 0);
+
+        // Corrupted copies are replaced, even when their length matches
+        std::fs::write(&path, "jello").unwrap();
+        store_blob(&storage, "hello".as_bytes()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello");
+    }
+
+    #[test]
+    fn blobs_pushed_again_are_kept_during_the_grace_period() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let blob = put_blob(&storage, "layer");
+        let past = SystemTime::now() - Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(blob.data_path(&storage))
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+
+        put_blob(&storage, "layer");
+        clean_storage(&storage, Duration::from_secs(60)).unwrap();
+        assert!(blob.data_path(&storage).exists());
+    }
+
+    #[test]
+    fn manifests_are_stored_and_resolved_through_the_library() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let layer = put_blob(&storage, "layer");
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json",
+            "config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{}","size":5}},
+            "layers":[]}}"#,
+            layer.to_digest()
+        );
+
+        assert!(list_tags(&storage, "foo/bar").unwrap().is_empty());
+        let blob = store_manifest(&storage, "foo/bar", "v1", manifest.as_bytes()).unwrap();
+        store_manifest(&storage, "foo/bar", "latest", manifest.as_bytes()).unwrap();
+
+        assert_eq!(list_tags(&storage, "foo/bar").unwrap(), vec!["latest", "v1"]);
+        assert_eq!(resolve_tag(&storage, "foo/bar", "v1").unwrap(), blob);
+        assert_eq!(get_docker_images_list(&storage).unwrap(), vec!["foo/bar"]);
+        assert_eq!(
+            DockerImage::new(&storage, "foo/bar").manifests_revision_list().unwrap(),
+            vec![blob]
+        );
+        assert!(matches!(
+            resolve_tag(&storage, "foo/bar", "v2"),
+            Err(RegistryError::ManifestUnknown(_))
+        ));
+
+        // Stored manifests keep their blobs from being collected
+        clean_storage(&storage, Duration::ZERO).unwrap();
+        assert!(layer.data_path(&storage).exists());
+    }
+
+    #[test]
+    fn invalid_manifests_are_not_stored_through_the_library() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let manifest = r#"{"schemaVersion":2,"mediaType":"application/vnd.oci.image.index.v1+json","manifests":[]}"#;
+
+        for (image, tag, manifest) in [
+            ("Foo", "latest", manifest.to_string()),
+            ("foo", "../latest", manifest.to_string()),
+            (
+                "foo",
+                "latest",
+                manifest.replace("\"schemaVersion\":2", "\"schemaVersion\":3"),
+            ),
+            ("foo", "latest", "not json".to_string()),
+        ] {
+            let res = store_manifest(&storage, image, tag, manifest.as_bytes());
+            assert!(
+                matches!(
+                    res,
+                    Err(RegistryError::NameInvalid(_)
+                        | RegistryError::TagInvalid(_)
+                        | RegistryError::ManifestInvalid(_))
+                ),
+                "{} {} {}",
+                image,
+                tag,
+                manifest
+            );
+        }
+
+        assert!(get_blob_list(&storage).unwrap().is_empty());
+        assert!(get_docker_images_list(&storage).unwrap().is_empty());
+    }
+
+    #[test]
+    fn gc_skips_foreign_layers() {
+        let storage = mktemp::Temp::new_dir().unwrap();
+        let config = put_blob(&storage, "{}");
+        let layer = put_blob(&storage, "layer");
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.docker.distribution.manifest.v2+json",
+            "config":{{"mediaType":"application/vnd.docker.container.image.v1+json","digest":"{}","size":2}},
+            "layers":[
+              {{"mediaType":"application/vnd.docker.image.rootfs.foreign.diff.tar.gzip",
+                "digest":"sha256:{}","size":1024,"urls":["https://example.com/base.tar.gz"]}},
+              {{"mediaType":"application/vnd.docker.image.rootfs.diff.tar.gzip","digest":"{}","size":5}}
+            ]}}"#,
+            config.to_digest(),
+            "f".repeat(64),
+            layer.to_digest()
+        );
+        let manifest = store_manifest(&storage, "windows", "latest", manifest.as_bytes()).unwrap();
+
+        clean_storage(&storage, Duration::ZERO).unwrap();
+        for blob in [&config, &layer, &manifest] {
+            assert!(blob.data_path(&storage).exists(), "{} was deleted", blob.to_digest());
+        }
+
+        let image = DockerImage::new(&storage, "windows");
+        assert_eq!(
+            image.referenced_blobs().unwrap(),
+            [&config, &layer, &manifest].map(BlobReference::to_digest).into()
+        );
+    }
+}
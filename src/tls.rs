@@ -0,0 +1,101 @@
+//! TLS termination, with optional authentication of clients by certificate
+
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use actix_tls::accept::rustls_0_21::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use rustls_pemfile::Item;
+
+use crate::server::ServerConfig;
+
+/// Identity of a client, taken from the common name of the certificate it authenticated with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientIdentity(pub String);
+
+fn read_certificates(path: &Path) -> std::io::Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(path)?))?;
+    if certs.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "no certificate found in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_private_key(path: &Path) -> std::io::Result<PrivateKey> {
+    rustls_pemfile::read_all(&mut BufReader::new(File::open(path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .ok_or_else(|| std::io::Error::other(format!("no private key found in {}", path.display())))
+}
+
+/// Build the TLS configuration of the server, if TLS is enabled
+pub fn server_config(conf: &ServerConfig) -> std::io::Result<Option<rustls::ServerConfig>> {
+    let (Some(cert_path), Some(key_path)) = (&conf.tls_cert_path, &conf.tls_key_path) else {
+        return Ok(None);
+    };
+
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+    let builder = match &conf.client_ca_path {
+        None => builder.with_no_client_auth(),
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certificates(ca_path)? {
+                roots.add(&cert).map_err(std::io::Error::other)?;
+            }
+
+            // Pulls do not require a certificate, so the handshake must succeed without one
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed())
+        }
+    };
+
+    builder
+        .with_single_cert(read_certificates(cert_path)?, read_private_key(key_path)?)
+        .map(Some)
+        .map_err(std::io::Error::other)
+}
+
+/// Extract the common name of a DER certificate
+fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let cn = cert.subject().iter_common_name().next()?;
+    cn.as_str().ok().map(str::to_string)
+}
+
+/// Get the TCP stream of a connection, whether it is served over TLS or not
+pub fn tcp_stream(conn: &dyn Any) -> Option<&TcpStream> {
+    match conn.downcast_ref::<TlsStream<TcpStream>>() {
+        Some(stream) => Some(stream.get_ref().0),
+        None => conn.downcast_ref::<TcpStream>(),
+    }
+}
+
+/// Record the identity of the clients authenticated by a certificate, to be registered with
+/// [`actix_web::HttpServer::on_connect`]
+///
+/// The certificate has already been verified against `client_ca_path` during the handshake
+pub fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    let (_, session) = stream.get_ref();
+    if let Some(cn) = session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| common_name(&cert.0))
+    {
+        ext.insert(ClientIdentity(cn));
+    }
+}
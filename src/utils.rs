@@ -1,12 +1,14 @@
 //! Utilities
 
-use std::io::{stdin, stdout, ErrorKind, Write};
-use std::path::Path;
+use std::io::{stdin, stdout, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use rand::distributions::Alphanumeric;
+use rand::rngs::OsRng;
 use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
 
 /// Create an empty file and all its parent directories
 pub fn create_empty_file(path: &Path) -> std::io::Result<()> {
@@ -16,12 +18,121 @@ pub fn create_empty_file(path: &Path) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Get the path of a hidden temporary file next to `path`, to be renamed to it
+fn temp_file_for(path: &Path) -> PathBuf {
+    path.parent().unwrap_or(path).join(format!(
+        ".{}.{}.tmp",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        rand_str(10)
+    ))
+}
+
+/// Atomically replace the content of a file, creating its parent directories if needed
+///
+/// The content is first written to a temporary file of the same directory, which is then
+/// renamed into place, so readers never observe a partially written file
+pub fn write_file_atomic(path: &Path, content: impl AsRef<[u8]>, durable: bool) -> std::io::Result<()> {
+    let parent = path.parent().unwrap_or(path);
+    std::fs::create_dir_all(parent)?;
+
+    let temp = temp_file_for(path);
+    std::fs::write(&temp, content)?;
+
+    if durable {
+        fsync(&temp)?;
+    }
+
+    if let Err(e) = std::fs::rename(&temp, path) {
+        let _ = std::fs::remove_file(&temp);
+        return Err(e);
+    }
+
+    if durable {
+        fsync(parent)?;
+    }
+
+    Ok(())
+}
+
+/// Atomically create a file with its content, failing with
+/// [`std::io::ErrorKind::AlreadyExists`] if it exists
+///
+/// The content is written to a temporary file which is then hard linked into place, so that
+/// the file is never observed empty
+pub fn create_file_atomic(path: &Path, content: impl AsRef<[u8]>) -> std::io::Result<()> {
+    let temp = temp_file_for(path);
+    std::fs::write(&temp, content)?;
+
+    let res = std::fs::hard_link(&temp, path);
+    let _ = std::fs::remove_file(&temp);
+    res
+}
+
+/// Move a file, copying it when the destination is on another filesystem
+pub fn move_file(from: &Path, to: &Path, durable: bool) -> std::io::Result<()> {
+    move_file_with(from, to, durable, |from, to| std::fs::rename(from, to))
+}
+
+fn move_file_with<R>(from: &Path, to: &Path, durable: bool, rename: R) -> std::io::Result<()>
+where
+    R: FnOnce(&Path, &Path) -> std::io::Result<()>,
+{
+    match rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            // Copy next to the destination first, so that a partial copy is never visible there
+            let parent = to.parent().unwrap_or(to);
+            let temp = temp_file_for(to);
+
+            // Unlike a rename, the copy wrote new data, which must be on the disk before the
+            // file is renamed
+            if let Err(e) = std::fs::copy(from, &temp)
+                .and_then(|_| fsync(&temp))
+                .and_then(|_| std::fs::rename(&temp, to))
+            {
+                let _ = std::fs::remove_file(&temp);
+                return Err(e);
+            }
+
+            if durable {
+                fsync(parent)?;
+            }
+
+            std::fs::remove_file(from)
+        }
+        res => res,
+    }
+}
+
+/// Flush the content of a file or a directory to the disk
+pub fn fsync(path: &Path) -> std::io::Result<()> {
+    #[cfg(test)]
+    FSYNC_CALLS.with(|c| c.set(c.get() + 1));
+
+    std::fs::File::open(path)?.sync_all()
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of calls to [`fsync`] made by the current thread
+    pub static FSYNC_CALLS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 /// Get the sha256 hash of a file
 pub fn sha256sum(path: &Path) -> std::io::Result<String> {
-    let output = Command::new("sha256sum").arg(path.as_os_str()).output()?;
+    checksum("sha256sum", path)
+}
+
+/// Get the sha512 hash of a file
+pub fn sha512sum(path: &Path) -> std::io::Result<String> {
+    checksum("sha512sum", path)
+}
+
+/// Get the hash of a file using a coreutils checksum program
+fn checksum(program: &str, path: &Path) -> std::io::Result<String> {
+    let output = Command::new(program).arg(path.as_os_str()).output()?;
 
     if !output.status.success() {
-        return Err(std::io::Error::new(ErrorKind::Other, "Failed to compute sum!"));
+        return Err(std::io::Error::other("Failed to compute sum!"));
     }
 
     let hash = String::from_utf8_lossy(&output.stdout)
@@ -35,10 +146,7 @@ pub fn sha256sum(path: &Path) -> std::io::Result<String> {
 
 /// Get the sha256 hash of a string
 pub fn sha256sum_str(str: &str) -> std::io::Result<String> {
-    let temp = mktemp::Temp::new_file()?;
-    std::fs::write(&temp, str)?;
-
-    sha256sum(&temp)
+    Ok(format!("{:x}", Sha256::digest(str.as_bytes())))
 }
 
 /// Request user's input
@@ -59,6 +167,29 @@ pub fn request_input(field: &str) -> std::io::Result<String> {
     Ok(s)
 }
 
+/// Request a secret from the user, without echoing it
+pub fn request_secret(field: &str) -> std::io::Result<String> {
+    print!("Please input {}: ", field);
+    stdout().flush()?;
+
+    // Secrets piped to the program are not echoed anyway
+    if !stdin().is_terminal() {
+        return read_secret(&mut stdin().lock());
+    }
+
+    rpassword::read_password()
+}
+
+/// Read a secret line from a reader
+fn read_secret(reader: &mut impl BufRead) -> std::io::Result<String> {
+    let mut s = String::new();
+    reader.read_line(&mut s)?;
+
+    let len = s.trim_end_matches(['\r', '\n']).len();
+    s.truncate(len);
+    Ok(s)
+}
+
 /// Generate a random string of a given size
 ///
 /// ```
@@ -76,6 +207,20 @@ pub fn rand_str(len: usize) -> String {
         .collect()
 }
 
+/// Generate a random alphanumeric secret of a given size, using the cryptographically
+/// secure random number generator of the operating system
+///
+/// ```
+/// use dockerust::utils::rand_secret;
+///
+/// let secret = rand_secret(50);
+/// assert_eq!(secret.len(), 50);
+/// assert!(secret.chars().all(|c| c.is_ascii_alphanumeric()));
+/// ```
+pub fn rand_secret(len: usize) -> String {
+    OsRng.sample_iter(&Alphanumeric).map(char::from).take(len).collect()
+}
+
 /// Get the current time since epoch
 ///
 /// ```
@@ -86,3 +231,97 @@ pub fn rand_str(len: usize) -> String {
 pub fn time() -> u64 {
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn atomic_writes_are_never_partially_observed() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = dir.join("current/link");
+        let values = [
+            "sha256:".to_string() + &"a".repeat(64),
+            "sha512:".to_string() + &"b".repeat(128),
+        ];
+        write_file_atomic(&path, &values[0], false).unwrap();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (path, values, stop) = (path.clone(), values.clone(), stop.clone());
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    write_file_atomic(&path, &values[i % 2], false).unwrap();
+                }
+                stop.store(true, Ordering::SeqCst);
+            })
+        };
+
+        while !stop.load(Ordering::SeqCst) {
+            let content = std::fs::read_to_string(&path).unwrap();
+            assert!(values.contains(&content), "partial read: {}", content);
+        }
+
+        writer.join().unwrap();
+        assert_eq!(std::fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn atomic_creations_keep_existing_files() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let path = dir.join("lock");
+
+        create_file_atomic(&path, "1").unwrap();
+        let err = create_file_atomic(&path, "2").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn strings_are_hashed_in_memory() {
+        assert_eq!(
+            sha256sum_str("hello").unwrap(),
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert_eq!(
+            sha256sum_str("").unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn files_are_copied_across_filesystems() {
+        let dir = mktemp::Temp::new_dir().unwrap();
+        let (from, to) = (dir.join("upload"), dir.join("blobs/data"));
+        std::fs::write(&from, "hello").unwrap();
+        create_empty_file(&to).unwrap();
+
+        let cross_devices = |_: &Path, _: &Path| Err(std::io::ErrorKind::CrossesDevices.into());
+        move_file_with(&from, &to, true, cross_devices).unwrap();
+        assert!(!from.exists());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "hello");
+        assert_eq!(std::fs::read_dir(dir.join("blobs")).unwrap().count(), 1);
+
+        // A failed copy leaves the destination untouched
+        assert!(move_file_with(&from, &to, true, cross_devices).is_err());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "hello");
+        assert_eq!(std::fs::read_dir(dir.join("blobs")).unwrap().count(), 1);
+
+        // Other errors are not recovered from
+        let denied = |_: &Path, _: &Path| Err(std::io::ErrorKind::PermissionDenied.into());
+        assert!(move_file_with(&to, &from, false, denied).is_err());
+        assert!(to.exists());
+    }
+
+    #[test]
+    fn secrets_are_read_without_line_endings() {
+        let mut input = std::io::Cursor::new("s3cr3t password\r\nnext line\n");
+        assert_eq!(read_secret(&mut input).unwrap(), "s3cr3t password");
+        assert_eq!(read_secret(&mut input).unwrap(), "next line");
+    }
+}